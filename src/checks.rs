@@ -0,0 +1,241 @@
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use reqwest::Client;
+use tokio::{fs, task};
+use tokio_util::sync::CancellationToken;
+
+use crate::filters::TrackFilter;
+use crate::models::{
+    AlbumInfo, DirectoryConfig, DownloadConfig, ExistingFiles, PlaylistFormat, SkipConfig,
+    WorkerConfig,
+};
+use crate::{downloaders, integrity, text_utils};
+
+/// compares the album directory with the current track list of the album and
+/// offers to download missing tracks. returns whether the album is complete
+#[expect(
+    clippy::too_many_arguments,
+    reason = "this function is called from a single place"
+)]
+pub async fn check_album(
+    client: Client,
+    url: &str,
+    output_path: &Path,
+    directories: DirectoryConfig,
+    config: DownloadConfig,
+    worker_config: WorkerConfig,
+    playlist_formats: Arc<[PlaylistFormat]>,
+    download_missing: bool,
+) -> bool {
+    let cancel = CancellationToken::new();
+
+    let Ok(resolved_album) =
+        downloaders::resolve_album_info(&client, url, &config, false, &cancel).await
+    else {
+        return false;
+    };
+
+    let album = resolved_album.info.clone();
+
+    let (album_path, is_grouped_single) =
+        find_album_directory(output_path, &album, directories).await;
+
+    tracing::info!(
+        "checking {} - {} in {}",
+        album.artist_name,
+        album.title,
+        album_path.display()
+    );
+
+    let file_names = read_audio_file_names(&album_path).await;
+    let missing_urls = compare_tracks(&album, is_grouped_single, file_names);
+    let missing_count = missing_urls.len();
+
+    if missing_count == 0 {
+        tracing::info!("no tracks are missing");
+        return true;
+    }
+
+    tracing::info!("{missing_count} tracks are missing");
+
+    if !download_missing && !confirm(format!("download {missing_count} missing tracks?")).await {
+        return false;
+    }
+
+    downloaders::download_album(
+        client,
+        resolved_album,
+        output_path,
+        ExistingFiles::Keep,
+        directories,
+        config,
+        worker_config,
+        // tracks saved under a different name aren't downloaded again
+        &TrackFilter {
+            track_urls: missing_urls,
+            ..TrackFilter::default()
+        },
+        SkipConfig {
+            tracks: false,
+            cover: false,
+            playlist: false,
+        },
+        playlist_formats,
+        None,
+        cancel,
+    )
+    .await;
+
+    // the directory is renamed when the format of the first track is appended
+    // to it
+    let (album_path, _) = find_album_directory(output_path, &album, directories).await;
+    let file_names = read_audio_file_names(&album_path).await;
+    let missing_count = compare_tracks(&album, is_grouped_single, file_names).len();
+
+    if missing_count > 0 {
+        tracing::warn!("{missing_count} tracks are still missing");
+    }
+
+    missing_count == 0
+}
+
+/// returns the directory of the album, which may have its audio format appended
+/// to its name, and whether it's the "Singles" directory
+async fn find_album_directory(
+    output_path: &Path,
+    album: &AlbumInfo,
+    directories: DirectoryConfig,
+) -> (PathBuf, bool) {
+    let (mut album_path, is_grouped_single) =
+        downloaders::album_directory(output_path, album, directories);
+
+    if directories.album_format
+        && !is_grouped_single
+        && let Some(formatted_path) = downloaders::find_formatted_directory(&album_path).await
+    {
+        album_path = formatted_path;
+    }
+
+    (album_path, is_grouped_single)
+}
+
+/// reports tracks that are missing, duplicated or saved under a different name
+/// and returns the URLs of the missing tracks
+fn compare_tracks(
+    album: &AlbumInfo,
+    is_grouped_single: bool,
+    mut file_names: Vec<String>,
+) -> Vec<String> {
+    let file_stems = album
+        .tracks
+        .iter()
+        .rev()
+        .map(|(track_number, track)| {
+            text_utils::format_track_stem(
+                track,
+                *track_number,
+                album.track_count,
+                is_grouped_single,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut missing_urls = Vec::new();
+
+    for ((_, track), file_stem) in album.tracks.iter().rev().zip(&file_stems) {
+        let sanitized_title = text_utils::sanitize_file_name(&track.title);
+        let mut matching_files = Vec::new();
+        let mut renamed_files = Vec::new();
+
+        file_names.retain(|file_name| {
+            let stem = file_name
+                .rsplit_once('.')
+                .map_or(file_name.as_str(), |(stem, _)| stem);
+
+            if stem == file_stem {
+                matching_files.push(file_name.clone());
+            } else if !file_stems.iter().any(|file_stem| file_stem == stem)
+                && (stem == sanitized_title || stem.ends_with(&format!(" {sanitized_title}")))
+            {
+                renamed_files.push(file_name.clone());
+            } else {
+                return true;
+            }
+
+            false
+        });
+
+        match (matching_files.len(), renamed_files.len()) {
+            (0, 0) => {
+                tracing::warn!("missing track {}", track.title);
+                missing_urls.push(track.url.clone());
+            }
+            (1, 0) => (),
+            (0, 1) => {
+                tracing::warn!(
+                    "track {} is saved as {}, expected {file_stem}",
+                    track.title,
+                    renamed_files[0]
+                );
+            }
+            _ => {
+                matching_files.extend(renamed_files);
+
+                tracing::warn!(
+                    "track {} is duplicated: {}",
+                    track.title,
+                    matching_files.join(", ")
+                );
+            }
+        }
+    }
+
+    // the "Singles" directory is shared with other albums
+    if !is_grouped_single {
+        for file_name in file_names {
+            tracing::warn!("file {file_name} doesn't match any track");
+        }
+    }
+
+    missing_urls
+}
+
+pub async fn read_audio_file_names(directory: &Path) -> Vec<String> {
+    let mut entries = match fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Vec::new(),
+        Err(err) => panic!("failed to read {}: {err}", directory.display()),
+    };
+
+    let mut file_names = Vec::new();
+
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        let file_name = entry.file_name().into_string().unwrap();
+
+        if entry.file_type().await.unwrap().is_file()
+            && file_name
+                .rsplit_once('.')
+                .is_some_and(|(_, extension)| integrity::AUDIO_FILE_EXTENSIONS.contains(&extension))
+        {
+            file_names.push(file_name);
+        }
+    }
+
+    file_names
+}
+
+async fn confirm(question: String) -> bool {
+    task::spawn_blocking(move || {
+        print!("{question} [y/N] ");
+        io::stdout().flush().unwrap();
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).unwrap();
+
+        answer.trim().eq_ignore_ascii_case("y")
+    })
+    .await
+    .unwrap()
+}
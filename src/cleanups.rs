@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+
+use tokio::fs;
+
+use crate::{checks, integrity, manifests};
+
+/// lists the part files of a run relative to the output directory, so they can
+/// be removed by a later run when this one is killed
+const JOURNAL_FILE_PREFIX: &str = ".lucida-parts-";
+const PART_FILE_EXTENSION: &str = "part";
+const COVER_FILE_NAME: &str = "cover.jpg";
+
+/// part files of this run's unfinished downloads
+static PART_FILES: OnceLock<Mutex<PartJournal>> = OnceLock::new();
+
+struct PartJournal {
+    output_path: PathBuf,
+    journal_path: PathBuf,
+    /// locked for as long as the run is going
+    file: File,
+    part_paths: HashSet<PathBuf>,
+}
+
+/// removes the part files left behind by earlier runs that were killed and
+/// starts recording the part files of this run. runs that are still going hold
+/// a lock on their journal, so their downloads are left alone
+pub async fn remove_orphaned_part_files(output_path: &Path) {
+    // the journal is created in the output directory, which doesn't exist yet
+    // on the first run into it
+    fs::create_dir_all(output_path).await.unwrap();
+
+    if let Ok(mut entries) = fs::read_dir(output_path).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(JOURNAL_FILE_PREFIX))
+            {
+                remove_journaled_part_files(&entry.path(), output_path).await;
+            }
+        }
+    }
+
+    let journal_path = output_path.join(format!("{JOURNAL_FILE_PREFIX}{}", process::id()));
+
+    let Ok(file) = File::create(&journal_path) else {
+        tracing::warn!(
+            "cannot create {}, unfinished downloads won't be removed if the run is killed",
+            journal_path.display()
+        );
+        return;
+    };
+
+    file.lock().unwrap();
+
+    PART_FILES.get_or_init(|| {
+        Mutex::new(PartJournal {
+            output_path: output_path.to_path_buf(),
+            journal_path,
+            file,
+            part_paths: HashSet::new(),
+        })
+    });
+}
+
+/// removes the part files of this run's unfinished downloads along with the
+/// album directories they leave empty
+pub async fn remove_part_files() {
+    let Some(part_files) = PART_FILES.get() else {
+        return;
+    };
+
+    let (part_paths, output_path, journal_path) = {
+        let mut part_files = part_files.lock().unwrap();
+
+        (
+            part_files.part_paths.drain().collect::<Vec<_>>(),
+            part_files.output_path.clone(),
+            part_files.journal_path.clone(),
+        )
+    };
+
+    for part_path in &part_paths {
+        remove_part_file(part_path, &output_path).await;
+    }
+
+    if !part_paths.is_empty() {
+        tracing::info!("removed {} unfinished downloads", part_paths.len());
+    }
+
+    fs::remove_file(journal_path).await.ok();
+}
+
+/// records a part file that is being downloaded to
+pub fn add_part_file(part_path: &Path) {
+    update_journal(|part_paths| part_paths.insert(part_path.to_path_buf()));
+}
+
+/// forgets a part file that was renamed or removed
+pub fn forget_part_file(part_path: &Path) {
+    update_journal(|part_paths| part_paths.remove(part_path));
+}
+
+fn update_journal(update: impl FnOnce(&mut HashSet<PathBuf>) -> bool) {
+    let Some(part_files) = PART_FILES.get() else {
+        return;
+    };
+
+    let mut part_files = part_files.lock().unwrap();
+
+    if update(&mut part_files.part_paths) {
+        part_files.save();
+    }
+}
+
+impl PartJournal {
+    fn save(&mut self) {
+        let contents = self
+            .part_paths
+            .iter()
+            .filter_map(|part_path| part_path.strip_prefix(&self.output_path).ok()?.to_str())
+            .fold(String::new(), |contents, part_path| {
+                contents + part_path + "\n"
+            });
+
+        if let Err(err) = self
+            .file
+            .set_len(0)
+            .and_then(|()| self.file.rewind())
+            .and_then(|()| self.file.write_all(contents.as_bytes()))
+        {
+            tracing::warn!("cannot update the list of unfinished downloads: {err}");
+        }
+    }
+}
+
+/// removes the part files listed in the journal of a run that isn't going
+/// anymore, and the journal itself
+async fn remove_journaled_part_files(journal_path: &Path, output_path: &Path) {
+    let Ok(mut file) = OpenOptions::new().read(true).write(true).open(journal_path) else {
+        return;
+    };
+
+    // the run is still going
+    if file.try_lock().is_err() {
+        return;
+    }
+
+    let contents = io::read_to_string(&mut file).unwrap_or_default();
+    let mut removed_count = 0;
+
+    for relative_path in contents.lines().map(Path::new) {
+        // only part files of tracks and covers are removed, within the output
+        // directory
+        if relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+            && relative_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(is_part_file_name)
+            && remove_part_file(&output_path.join(relative_path), output_path).await
+        {
+            removed_count += 1;
+        }
+    }
+
+    if removed_count > 0 {
+        tracing::info!("removed {removed_count} unfinished downloads of an earlier run");
+    }
+
+    drop(file);
+    fs::remove_file(journal_path).await.ok();
+}
+
+/// whether the file name is one of a track or cover part file, e.g.
+/// "01. Title.flac.part"
+fn is_part_file_name(file_name: &str) -> bool {
+    let Some((file_name, PART_FILE_EXTENSION)) = file_name.rsplit_once('.') else {
+        return false;
+    };
+
+    file_name == COVER_FILE_NAME
+        || file_name.rsplit_once('.').is_some_and(|(_, extension)| {
+            integrity::AUDIO_FILE_EXTENSIONS.contains(&extension)
+                || extension == integrity::GENERIC_FILE_EXTENSION
+        })
+}
+
+/// returns whether the part file was removed
+async fn remove_part_file(part_path: &Path, output_path: &Path) -> bool {
+    if fs::remove_file(part_path).await.is_err() {
+        return false;
+    }
+
+    tracing::info!("removed unfinished download {}", part_path.display());
+
+    if let Some(directory) = part_path.parent() {
+        remove_empty_directories(directory, output_path).await;
+    }
+
+    true
+}
+
+/// removes the album directory created for this album when none of its tracks
+/// ended up downloaded, e.g. when all of them were unavailable. only the files
+/// written by lucida-downloader are removed, the directory is kept if anything
+/// else is in it
+pub async fn remove_album_without_audio(album_path: &Path, output_path: &Path) {
+    if !checks::read_audio_file_names(album_path).await.is_empty() {
+        return;
+    }
+
+    let Ok(mut entries) = fs::read_dir(album_path).await else {
+        return;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(file_name) = entry.file_name().into_string() else {
+            continue;
+        };
+
+        if entry
+            .file_type()
+            .await
+            .is_ok_and(|file_type| file_type.is_file())
+            && (file_name == COVER_FILE_NAME || manifests::is_ignored_file(&file_name))
+        {
+            fs::remove_file(entry.path()).await.ok();
+        }
+    }
+
+    if fs::remove_dir(album_path).await.is_err() {
+        tracing::warn!(
+            "album directory {} has no audio, but other files",
+            album_path.display()
+        );
+        return;
+    }
+
+    tracing::warn!(
+        "removed album directory {} without audio",
+        album_path.display()
+    );
+
+    if let Some(parent) = album_path.parent() {
+        remove_empty_directories(parent, output_path).await;
+    }
+}
+
+/// removes the directory and its parents up to the output directory as long
+/// as they are empty
+async fn remove_empty_directories(directory: &Path, output_path: &Path) {
+    for directory in directory.ancestors() {
+        // the directory isn't removed when it's not empty
+        if directory == output_path
+            || !directory.starts_with(output_path)
+            || fs::remove_dir(directory).await.is_err()
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, BufRead, IsTerminal};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+/// whether new requests and active downloads are paused
+static PAUSED: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+
+/// total time the downloads were paused for, and when the current pause
+/// started
+static PAUSED_TIME: Mutex<(Duration, Option<Instant>)> = Mutex::new((Duration::ZERO, None));
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// pauses and resumes the downloads on SIGUSR1 and SIGUSR2, or when Enter is
+/// pressed in the terminal
+pub fn listen() {
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut pause_signals = signal(SignalKind::user_defined1()).unwrap();
+        let mut resume_signals = signal(SignalKind::user_defined2()).unwrap();
+
+        loop {
+            tokio::select! {
+                _ = pause_signals.recv() => pause(),
+                _ = resume_signals.recv() => resume(),
+            }
+        }
+    });
+
+    if !io::stdin().is_terminal() {
+        return;
+    }
+
+    tracing::info!("press Enter to pause or resume");
+
+    // reading stdin on the runtime would keep it from shutting down until
+    // Enter is pressed
+    thread::spawn(|| {
+        for _ in io::stdin().lock().lines().map_while(Result::ok) {
+            if *PAUSED.borrow() {
+                resume();
+            } else {
+                pause();
+            }
+        }
+    });
+}
+
+pub fn pause() {
+    if !PAUSED.send_replace(true) {
+        tracing::warn!("paused");
+        PAUSED_TIME.lock().unwrap().1 = Some(Instant::now());
+    }
+}
+
+pub fn resume() {
+    if PAUSED.send_replace(false) {
+        tracing::warn!("resumed");
+
+        let mut paused_time = PAUSED_TIME.lock().unwrap();
+
+        if let Some(pause_start) = paused_time.1.take() {
+            paused_time.0 += pause_start.elapsed();
+        }
+    }
+}
+
+/// waits while the downloads are paused, unless they are cancelled
+pub async fn wait_until_resumed(cancel: &CancellationToken) {
+    let mut paused = PAUSED.subscribe();
+
+    if let Some(result) = cancel
+        .run_until_cancelled(paused.wait_for(|&is_paused| !is_paused))
+        .await
+    {
+        result.unwrap();
+    }
+}
+
+/// time the downloads were running for, without the time they were paused.
+/// used to measure timeouts that shouldn't run out while paused
+pub fn running_time() -> Duration {
+    let (paused_time, pause_start) = *PAUSED_TIME.lock().unwrap();
+    let current_pause = pause_start.map_or(Duration::ZERO, |pause_start| pause_start.elapsed());
+
+    START
+        .elapsed()
+        .saturating_sub(paused_time)
+        .saturating_sub(current_pause)
+}
+
+/// sleeps until the downloads were running for the duration, the time they are
+/// paused for isn't counted
+pub async fn sleep_running(duration: Duration) {
+    let start = running_time();
+
+    loop {
+        let remaining = duration.saturating_sub(running_time().saturating_sub(start));
+
+        if remaining.is_zero() {
+            break;
+        }
+
+        time::sleep(remaining).await;
+    }
+}
@@ -1,21 +1,45 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{ErrorKind, SeekFrom};
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use futures::future;
+use futures::future::{self, OptionFuture};
+use regex::Regex;
 use reqwest::Client;
-use tokio::fs::File;
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::{fs, time};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::{fs, sync, time};
+use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
 
+use crate::filters::TrackFilter;
+use crate::integrity::{AudioFormat, AudioVerifier};
 use crate::models::{
-    AlbumInfo, AlbumYear, DownloadConfig, PageData, ResolveAlbumError, Service, SkipConfig, Track,
-    TrackDownload,
+    Album, AlbumInfo, AlbumYear, DirectoryConfig, DownloadConfig, DownloadedTrack, ExistingFiles,
+    Info, PageData, PageTokens, PlaylistFormat, ProcessedTrack, ProcessingStatus, Quality,
+    RemovedTracks, RequestTrackError, ResolveAlbumError, ResolvedAlbum, Service, SkipConfig, Token,
+    Track, TrackDownload, TrackHandoff, TrackLocation, TrackStream, UnavailableReason,
+    WorkerConfig,
 };
-use crate::{requests, text_utils, workers};
+use crate::{cleanups, controls, integrity, manifests, playlists, requests, text_utils, workers};
+
+const MAX_FAILED_VERIFICATIONS: u32 = 3;
+/// size of the reads of the headers holding the audio format of a file
+const FORMAT_READ_BUFFER_SIZE: usize = 64 * 1024;
+/// times lucida may fail to process a track before giving up on it
+const MAX_PROCESSING_ERRORS: u32 = 3;
+/// times the tokens may be refreshed for a track before giving up on it
+const MAX_TOKEN_REFRESHES: u32 = 3;
+/// times lucida may fail to resolve a page before giving up on it, so one
+/// broken URL doesn't hold up the others
+const MAX_RESOLVE_ATTEMPTS: u32 = 12;
+
+/// titles, URLs and reasons of tracks skipped as unavailable
+static UNAVAILABLE_TRACKS: Mutex<Vec<(String, String, UnavailableReason)>> = Mutex::new(Vec::new());
 
 #[expect(
     clippy::too_many_arguments,
@@ -23,33 +47,23 @@ use crate::{requests, text_utils, workers};
 )]
 pub async fn download_album(
     client: Client,
-    url: &str,
+    resolved_album: ResolvedAlbum,
     output_path: &Path,
-    force_download: bool,
-    group_singles: bool,
-    album_year: Option<AlbumYear>,
-    flatten_directories: bool,
+    existing_files: ExistingFiles,
+    directories: DirectoryConfig,
     config: DownloadConfig,
-    track_workers: usize,
+    worker_config: WorkerConfig,
+    filter: &TrackFilter,
     skip: SkipConfig,
-    running: Arc<AtomicBool>,
+    playlist_formats: Arc<[PlaylistFormat]>,
+    sync: Option<RemovedTracks>,
+    cancel: CancellationToken,
 ) {
-    let Some(page_data) = resolve_album(&client, url, &config, &running).await else {
-        return;
-    };
-
-    let album = match AlbumInfo::new(page_data.info, page_data.token) {
-        Ok(album) => album,
-        Err(err) => {
-            match err {
-                ResolveAlbumError::ArtistUrl { name } => {
-                    tracing::error!("cannot download URL pointing to an artist ({name})");
-                }
-            }
-
-            return;
-        }
-    };
+    let ResolvedAlbum {
+        info: mut album,
+        service,
+        tokens,
+    } = resolved_album;
 
     tracing::info!(
         "downloading album {} - {} with {} tracks",
@@ -58,123 +72,502 @@ pub async fn download_album(
         album.track_count
     );
 
-    let is_grouped_single = group_singles
+    let (mut album_path, is_grouped_single) = album_directory(output_path, &album, directories);
+    let unformatted_path = album_path.clone();
+    let is_playlist_library = album.is_playlist && directories.playlist_library;
+
+    // the "Singles" directory is shared with other albums and playlist library
+    // tracks are spread across their own album directories
+    let is_formatted = directories.album_format && !is_grouped_single && !is_playlist_library;
+    let (is_format_pending, is_created) =
+        create_album_directory(&mut album_path, is_formatted).await;
+
+    // playlist library directories only ever contain playlist files, and
+    // directories that were there before may hold files of the user
+    let is_removable = directories.remove_empty_albums
+        && is_created
+        && !(skip.tracks || is_grouped_single || is_playlist_library);
+
+    if let Some(removed_tracks) = sync
+        && album.is_playlist
+        && !is_playlist_library
+        && !skip.tracks
+    {
+        playlists::sync_playlist(
+            &album_path,
+            &album.tracks,
+            album.track_count,
+            removed_tracks,
+        )
+        .await;
+    }
+
+    filter_tracks(&mut album.tracks, filter);
+
+    let mut album_path = Arc::new(album_path);
+
+    if !skip.tracks {
+        let tracks = locate_tracks(
+            output_path,
+            album.tracks,
+            TrackLocation {
+                directory: album_path.clone(),
+                track_number: None,
+                track_count: album.track_count,
+                is_grouped_single,
+            },
+            is_playlist_library.then_some(directories),
+        )
+        .await;
+
+        let mut downloaded_tracks = download_tracks(
+            &client,
+            service,
+            tracks,
+            worker_config,
+            is_format_pending.then_some(&mut album_path),
+            &Arc::new(sync::Mutex::new(tokens)),
+            existing_files,
+            &config,
+            &cancel,
+        )
+        .await;
+
+        if is_formatted && existing_files == ExistingFiles::Upgrade {
+            update_album_format(&mut album_path, &unformatted_path, &mut downloaded_tracks).await;
+        }
+
+        record_tracks(service, &downloaded_tracks).await;
+
+        if album.is_playlist && !cancel.is_cancelled() {
+            playlists::save_playlist(
+                &album_path,
+                &album.title,
+                if skip.playlist {
+                    &[]
+                } else {
+                    &playlist_formats
+                },
+                downloaded_tracks,
+            )
+            .await;
+        }
+    }
+
+    if !skip.cover && !is_grouped_single && !cancel.is_cancelled() {
+        download_album_cover(
+            client,
+            &album.title,
+            service,
+            &album.cover_artwork_url,
+            existing_files,
+            &album_path,
+            cancel.clone(),
+        )
+        .await;
+    }
+
+    if is_removable && !cancel.is_cancelled() {
+        cleanups::remove_album_without_audio(&album_path, output_path).await;
+    }
+}
+
+fn add_unavailable_track(track: &Track, reason: UnavailableReason) {
+    UNAVAILABLE_TRACKS
+        .lock()
+        .unwrap()
+        .push((track.title.clone(), track.url.clone(), reason));
+}
+
+/// lists the tracks skipped as unavailable
+pub fn report_unavailable_tracks() {
+    let unavailable_tracks = mem::take(&mut *UNAVAILABLE_TRACKS.lock().unwrap());
+
+    if unavailable_tracks.is_empty() {
+        return;
+    }
+
+    tracing::warn!("skipped {} unavailable tracks:", unavailable_tracks.len());
+
+    for (title, url, reason) in unavailable_tracks {
+        tracing::warn!("{title} ({url}): {reason}");
+    }
+}
+
+/// returns the directory the album is downloaded to and whether it's a grouped
+/// single
+pub fn album_directory(
+    output_path: &Path,
+    album: &AlbumInfo,
+    directories: DirectoryConfig,
+) -> (PathBuf, bool) {
+    let is_grouped_single = directories.group_singles
         && album.track_count == 1
         && album
             .tracks
             .iter()
             .all(|track| track.1.title == album.title);
 
-    let album_path = {
-        let sanitized_artist_name = text_utils::sanitize_file_name(&album.artist_name);
+    let album_path = format_album_path(
+        output_path,
+        &album.artist_name,
+        &album.title,
+        album.release_year,
+        is_grouped_single,
+        directories,
+    );
 
-        let album_directory = if is_grouped_single {
-            "Singles".into()
-        } else {
-            let sanitized_album_title = text_utils::sanitize_file_name(&album.title);
+    (album_path, is_grouped_single)
+}
 
-            match (album.release_year, album_year) {
-                (Some(release_year), Some(AlbumYear::Append)) => {
-                    format!("{sanitized_album_title} ({release_year})")
-                }
-                (Some(release_year), Some(AlbumYear::Prepend)) => {
-                    format!("({release_year}) {sanitized_album_title}")
-                }
-                _ => sanitized_album_title,
+fn format_album_path(
+    output_path: &Path,
+    artist_name: &str,
+    title: &str,
+    release_year: Option<u16>,
+    is_grouped_single: bool,
+    directories: DirectoryConfig,
+) -> PathBuf {
+    let sanitized_artist_name = text_utils::sanitize_file_name(artist_name);
+
+    let album_directory = if is_grouped_single {
+        "Singles".into()
+    } else {
+        let sanitized_album_title = text_utils::sanitize_file_name(title);
+
+        match (release_year, directories.album_year) {
+            (Some(release_year), Some(AlbumYear::Append)) => {
+                format!("{sanitized_album_title} ({release_year})")
             }
-        };
+            (Some(release_year), Some(AlbumYear::Prepend)) => {
+                format!("({release_year}) {sanitized_album_title}")
+            }
+            _ => sanitized_album_title,
+        }
+    };
 
-        let album_directory = if flatten_directories {
-            vec![format!("{sanitized_artist_name} - {album_directory}")]
-        } else {
-            vec![sanitized_artist_name, album_directory]
-        };
+    let album_directory = if directories.flatten_directories {
+        vec![format!("{sanitized_artist_name} - {album_directory}")]
+    } else {
+        vec![sanitized_artist_name, album_directory]
+    };
+
+    let mut album_path = PathBuf::from(output_path);
+    album_path.extend(album_directory);
 
-        let mut album_path = PathBuf::from(output_path);
-        album_path.extend(album_directory);
+    album_path
+}
 
-        album_path
+/// creates the album directory, switching to an existing one with the audio
+/// format appended when `append_format` is set. returns whether the format is
+/// yet to be appended and whether the directory was created
+async fn create_album_directory(album_path: &mut PathBuf, append_format: bool) -> (bool, bool) {
+    let formatted_path = if append_format {
+        find_formatted_directory(album_path).await
+    } else {
+        None
     };
 
+    let is_format_pending = append_format && formatted_path.is_none();
+
+    if let Some(formatted_path) = formatted_path {
+        *album_path = formatted_path;
+    }
+
+    let is_created = !fs::try_exists(&album_path).await.unwrap();
     fs::create_dir_all(&album_path).await.unwrap();
 
-    let tracks_len = album.tracks.len();
-    let tracks = Arc::new(Mutex::new(album.tracks));
-    let album_path = Arc::new(album_path);
+    (is_format_pending, is_created)
+}
 
-    if !skip.tracks {
-        let worker_count = track_workers.min(tracks_len);
+/// finds a directory of the album with the audio format already appended to its
+/// name
+pub async fn find_formatted_directory(album_path: &Path) -> Option<PathBuf> {
+    static FORMAT_SUFFIX: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^ \[((FLAC|WAV) \d+-[\d.]+|MP3 (\d+|VBR)|(AAC|VORBIS)( \d+)?|ALAC|OPUS)\]$")
+            .unwrap()
+    });
 
-        tracing::info!("spawning {worker_count} track workers");
+    let album_directory = album_path.file_name().unwrap().to_str().unwrap();
 
-        for result in future::join_all((1..=worker_count).map(|track_worker| {
-            tokio::spawn(
-                workers::run_track_worker(
-                    client.clone(),
-                    page_data.original_service,
-                    tracks.clone(),
-                    album.track_count,
-                    is_grouped_single,
-                    page_data.token_expiry,
-                    force_download,
-                    config.clone(),
-                    album_path.clone(),
-                    running.clone(),
-                )
-                .instrument(tracing::info_span!("track", track_worker)),
-            )
-        }))
-        .await
+    let mut entries = match fs::read_dir(album_path.parent().unwrap()).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => panic!("failed to read {}: {err}", album_path.display()),
+    };
+
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        if entry.file_type().await.unwrap().is_dir()
+            && let Some(suffix) = entry
+                .file_name()
+                .to_str()
+                .and_then(|file_name| file_name.strip_prefix(album_directory))
+            && FORMAT_SUFFIX.is_match(suffix)
         {
-            result.unwrap();
+            return Some(entry.path());
         }
     }
 
-    if skip.cover || is_grouped_single || !running.load(Ordering::Relaxed) {
-        return;
+    None
+}
+
+/// appends the audio format of the track to the unformatted album directory
+/// name and renames the directory. returns the new path of the directory if it
+/// changed
+async fn append_album_format(
+    album_path: &Path,
+    unformatted_path: &Path,
+    track_path: &Path,
+) -> Option<PathBuf> {
+    let Some(format) = read_file_format(track_path).await else {
+        tracing::warn!(
+            "cannot determine the audio format of {}, keeping the album directory name",
+            track_path.display()
+        );
+
+        return None;
+    };
+
+    let mut album_directory = unformatted_path.file_name().unwrap().to_os_string();
+    album_directory.push(format!(" [{format}]"));
+
+    let formatted_path = album_path.with_file_name(album_directory);
+
+    if formatted_path == album_path {
+        return None;
     }
 
-    download_album_cover(
-        client,
-        &album.title,
-        page_data.original_service,
-        &album.cover_artwork_url,
-        force_download,
-        &album_path,
-        running,
-    )
-    .await;
+    fs::rename(album_path, &formatted_path).await.unwrap();
+
+    tracing::info!("renamed album directory to {}", formatted_path.display());
+
+    Some(formatted_path)
+}
+
+/// reads the audio format from the headers of the file, seeking past the audio
+/// data in between
+async fn read_file_format(path: &Path) -> Option<AudioFormat> {
+    let file_extension = path.extension()?.to_str()?;
+    let file_extension = integrity::AUDIO_FILE_EXTENSIONS
+        .into_iter()
+        .find(|extension| *extension == file_extension)?;
+
+    let mut verifier = AudioVerifier::new(Some(file_extension));
+    let mut file = File::open(path).await.unwrap();
+    let mut buffer = vec![0; FORMAT_READ_BUFFER_SIZE];
+
+    while verifier.format().is_none() {
+        let skip = verifier.take_skip();
+
+        if skip > 0 {
+            file.seek(SeekFrom::Current(i64::try_from(skip).ok()?))
+                .await
+                .unwrap();
+        }
+
+        let length = file.read(&mut buffer).await.unwrap();
+
+        if length == 0 {
+            break;
+        }
+
+        verifier.update(&buffer[..length]).ok()?;
+    }
+
+    verifier.into_format()
+}
+
+fn filter_tracks(tracks: &mut Vec<(Option<u32>, Track)>, filter: &TrackFilter) {
+    let tracks_len = tracks.len();
+
+    tracks.retain(|(track_number, track)| {
+        let rejection = filter.rejection(*track_number, track);
+
+        if let Some(rejection) = rejection {
+            tracing::info!("filtered out track {}: {rejection}", track.title);
+        }
+
+        rejection.is_none()
+    });
+
+    if tracks.len() < tracks_len {
+        tracing::info!("filtered out {} tracks", tracks_len - tracks.len());
+    }
+}
+
+/// places every track in the album directory, or in the directory of its own
+/// album when `library_directories` is set
+async fn locate_tracks(
+    output_path: &Path,
+    tracks: Vec<(Option<u32>, Track)>,
+    album_location: TrackLocation,
+    library_directories: Option<DirectoryConfig>,
+) -> Vec<(Option<u32>, Track, TrackLocation)> {
+    let mut located_tracks = Vec::with_capacity(tracks.len());
+
+    for (track_number, track) in tracks {
+        let library_location = library_directories.and_then(|directories| {
+            let Some(album) = &track.album else {
+                tracing::warn!(
+                    "track {} has no album, placing it in the playlist directory",
+                    track.title
+                );
+
+                return None;
+            };
+
+            let track_count = album.track_count.unwrap_or(1);
+
+            let is_grouped_single =
+                directories.group_singles && track_count == 1 && album.title == track.title;
+
+            let artist = album.artists.first().or_else(|| track.artists.first());
+
+            Some(TrackLocation {
+                directory: Arc::new(format_album_path(
+                    output_path,
+                    artist.map_or("Unknown", |artist| &artist.name),
+                    &album.title,
+                    album
+                        .release_date
+                        .map(|release_date| release_date.year().try_into().unwrap()),
+                    is_grouped_single,
+                    directories,
+                )),
+                track_number: track.number,
+                track_count,
+                is_grouped_single,
+            })
+        });
+
+        let location = if let Some(mut location) = library_location {
+            // the album may already be in the library with its audio format
+            // appended to its directory name
+            if library_directories.is_some_and(|directories| directories.album_format)
+                && !location.is_grouped_single
+                && let Some(formatted_path) = find_formatted_directory(&location.directory).await
+            {
+                location.directory = Arc::new(formatted_path);
+            }
+
+            fs::create_dir_all(location.directory.as_path())
+                .await
+                .unwrap();
+
+            location
+        } else {
+            TrackLocation {
+                track_number,
+                ..album_location.clone()
+            }
+        };
+
+        located_tracks.push((track_number, track, location));
+    }
+
+    located_tracks
+}
+
+pub async fn resolve_album_info(
+    client: &Client,
+    url: &str,
+    config: &DownloadConfig,
+    expand_tracks: bool,
+    cancel: &CancellationToken,
+) -> Result<ResolvedAlbum, ResolveAlbumError> {
+    let Some(page_data) = resolve_page(client, url, config, expand_tracks, cancel).await else {
+        return Err(ResolveAlbumError::Unresolved);
+    };
+
+    match AlbumInfo::new(page_data.info, page_data.token) {
+        Ok(album) => Ok(ResolvedAlbum {
+            info: album,
+            service: page_data.original_service,
+            tokens: PageTokens {
+                url: url.to_owned(),
+                expand_tracks,
+                expiry: page_data.token_expiry,
+                tracks: HashMap::new(),
+            },
+        }),
+        Err(err) => {
+            if let ResolveAlbumError::ArtistUrl { name } = &err {
+                tracing::error!("cannot download URL pointing to an artist ({name})");
+            }
+
+            Err(err)
+        }
+    }
+}
+
+async fn resolve_page(
+    client: &Client,
+    url: &str,
+    config: &DownloadConfig,
+    expand_tracks: bool,
+    cancel: &CancellationToken,
+) -> Option<PageData> {
+    let page_data = resolve_album(client, url, config, cancel).await?;
+
+    if !expand_tracks {
+        return Some(page_data);
+    }
+
+    match &page_data.info {
+        Info::Track {
+            album:
+                Some(Album {
+                    url: Some(album_url),
+                    ..
+                }),
+            ..
+        } => {
+            tracing::info!("expanding track to its album");
+            resolve_album(client, album_url, config, cancel).await
+        }
+        Info::Track { title, .. } => {
+            tracing::warn!("cannot expand track {title} to its album: album URL is unknown");
+            Some(page_data)
+        }
+        Info::Album { .. } | Info::Playlist { .. } | Info::Artist { .. } => Some(page_data),
+    }
 }
 
 async fn resolve_album(
     client: &Client,
     url: &str,
     config: &DownloadConfig,
-    running: &Arc<AtomicBool>,
+    cancel: &CancellationToken,
 ) -> Option<PageData> {
     tracing::info!("resolving album {url}");
 
+    let mut attempts = 1;
+
     let html = loop {
-        let html = requests::resolve_album(client, url, &config.country, running).await?;
+        if let Some(html) = requests::resolve_album(client, url, &config.country, cancel).await {
+            let Some(error) = [
+                "An error occured trying to process your request.",
+                "Message: \"Cannot contact any valid server\"",
+                "An error occurred. Had an issue getting that item, try again.",
+            ]
+            .into_iter()
+            .find(|&error| html.contains(error)) else {
+                break html;
+            };
 
-        if let Some(error) = [
-            "An error occured trying to process your request.",
-            "Message: \"Cannot contact any valid server\"",
-            "An error occurred. Had an issue getting that item, try again.",
-        ]
-        .into_iter()
-        .find(|&error| html.contains(error))
-        {
             tracing::warn!("HTML contains error: {error}");
+        }
 
-            if !running.load(Ordering::Relaxed) {
-                return None;
-            }
-
-            time::sleep(Duration::from_secs(5)).await;
-        } else {
-            break html;
+        if attempts == MAX_RESOLVE_ATTEMPTS {
+            tracing::error!("giving up on resolving {url} after {attempts} attempts");
+            return None;
         }
+
+        cancel
+            .run_until_cancelled(time::sleep(Duration::from_secs(5)))
+            .await?;
+
+        attempts += 1;
     };
 
     Some(
@@ -187,35 +580,253 @@ async fn resolve_album(
     )
 }
 
+/// downloads tracks one by one until one of them lands, as the audio format
+/// appended to the album directory name is only known then. the remaining
+/// tracks are moved to the renamed directory
 #[expect(
     clippy::too_many_arguments,
     reason = "this function is called from a single place"
 )]
-pub async fn request_and_download_track(
-    client: Client,
+async fn download_first_track(
+    client: &Client,
     service: Service,
-    track: &Track,
-    track_number: Option<u32>,
-    track_count: u32,
-    is_grouped_single: bool,
-    token_expiry: u64,
-    force_download: bool,
+    tracks: &mut Vec<(Option<u32>, Track, TrackLocation)>,
+    album_path: &mut Arc<PathBuf>,
+    tokens: &Arc<sync::Mutex<PageTokens>>,
+    existing_files: ExistingFiles,
     config: &DownloadConfig,
-    album_path: Arc<PathBuf>,
-    running: Arc<AtomicBool>,
+    cancel: &CancellationToken,
+) -> Vec<DownloadedTrack> {
+    let mut downloaded_tracks = Vec::new();
+
+    while downloaded_tracks.is_empty()
+        && !cancel.is_cancelled()
+        && let Some(track) = tracks.pop()
+    {
+        downloaded_tracks = spawn_track_workers(
+            client,
+            service,
+            vec![track],
+            WorkerConfig {
+                track_workers: 1,
+                download_workers: 1,
+            },
+            tokens,
+            existing_files,
+            config,
+            cancel,
+        )
+        .await;
+    }
+
+    if let Some(first_track) = downloaded_tracks.first_mut()
+        && let Some(formatted_path) =
+            append_album_format(album_path, album_path, &first_track.path).await
+    {
+        first_track.path = formatted_path.join(first_track.path.file_name().unwrap());
+        *album_path = Arc::new(formatted_path);
+
+        for (.., location) in tracks {
+            location.directory = album_path.clone();
+        }
+    }
+
+    downloaded_tracks
+}
+
+/// records the downloaded tracks in the manifests of their directories
+async fn record_tracks(service: Service, downloaded_tracks: &[DownloadedTrack]) {
+    manifests::record_files(
+        service,
+        downloaded_tracks
+            .iter()
+            .map(|track| (track.path.clone(), track.track.url.clone(), track.quality))
+            .collect(),
+    )
+    .await;
+}
+
+/// renames the album directory when the tracks were upgraded to another format
+/// than the one in its name
+async fn update_album_format(
+    album_path: &mut Arc<PathBuf>,
+    unformatted_path: &Path,
+    downloaded_tracks: &mut [DownloadedTrack],
 ) {
-    // HACK(jel): this seems to be the only way to detect tracks that are impossible
-    // to download yet
-    if matches!(service, Service::Qobuz if track.producers.is_none()) {
-        tracing::error!("skipping unavailable track {}", track.title);
+    // tracks downloaded by this run have their quality recorded
+    let Some(downloaded_track) = downloaded_tracks
+        .iter()
+        .find(|track| track.quality.is_some())
+    else {
+        return;
+    };
+
+    let Some(formatted_path) =
+        append_album_format(album_path, unformatted_path, &downloaded_track.path).await
+    else {
         return;
+    };
+
+    for track in downloaded_tracks {
+        track.path = formatted_path.join(track.path.file_name().unwrap());
+    }
+
+    *album_path = Arc::new(formatted_path);
+}
+
+/// downloads the tracks, appending the audio format to the album directory name
+/// first when `format_album_path` is set
+#[expect(
+    clippy::too_many_arguments,
+    reason = "this function is called from a single place"
+)]
+async fn download_tracks(
+    client: &Client,
+    service: Service,
+    mut tracks: Vec<(Option<u32>, Track, TrackLocation)>,
+    worker_config: WorkerConfig,
+    format_album_path: Option<&mut Arc<PathBuf>>,
+    tokens: &Arc<sync::Mutex<PageTokens>>,
+    existing_files: ExistingFiles,
+    config: &DownloadConfig,
+    cancel: &CancellationToken,
+) -> Vec<DownloadedTrack> {
+    let mut downloaded_tracks = if let Some(album_path) = format_album_path {
+        download_first_track(
+            client,
+            service,
+            &mut tracks,
+            album_path,
+            tokens,
+            existing_files,
+            config,
+            cancel,
+        )
+        .await
+    } else {
+        Vec::new()
+    };
+
+    downloaded_tracks.extend(
+        spawn_track_workers(
+            client,
+            service,
+            tracks,
+            worker_config,
+            tokens,
+            existing_files,
+            config,
+            cancel,
+        )
+        .await,
+    );
+
+    downloaded_tracks
+}
+
+/// spawns the track workers, which have lucida process the tracks, and the
+/// download workers, which download the processed tracks
+#[expect(
+    clippy::too_many_arguments,
+    reason = "this function is called from two places"
+)]
+async fn spawn_track_workers(
+    client: &Client,
+    service: Service,
+    tracks: Vec<(Option<u32>, Track, TrackLocation)>,
+    worker_config: WorkerConfig,
+    tokens: &Arc<sync::Mutex<PageTokens>>,
+    existing_files: ExistingFiles,
+    config: &DownloadConfig,
+    cancel: &CancellationToken,
+) -> Vec<DownloadedTrack> {
+    let worker_count = worker_config.track_workers.min(tracks.len());
+    let download_worker_count = worker_config.download_workers.min(tracks.len()).max(1);
+    let mut downloaded_tracks = Vec::with_capacity(tracks.len());
+    let tracks = Arc::new(Mutex::new(tracks));
+
+    // processed tracks wait here for a download worker, the track workers stop
+    // requesting new tracks while it's full
+    let (handoffs_tx, handoffs_rx) = mpsc::channel(download_worker_count);
+    let handoffs_rx = Arc::new(sync::Mutex::new(handoffs_rx));
+
+    tracing::info!(
+        "spawning {worker_count} track workers and {download_worker_count} download workers"
+    );
+
+    let track_workers = future::join_all((1..=worker_count).map(|track_worker| {
+        tokio::spawn(
+            workers::run_track_worker(
+                client.clone(),
+                service,
+                tracks.clone(),
+                tokens.clone(),
+                existing_files,
+                config.clone(),
+                handoffs_tx.clone(),
+                cancel.clone(),
+            )
+            .instrument(tracing::info_span!("track", track_worker)),
+        )
+    }));
+
+    // the download workers stop once every track worker dropped its sender
+    drop(handoffs_tx);
+
+    let download_workers = future::join_all((1..=download_worker_count).map(|download_worker| {
+        tokio::spawn(
+            workers::run_download_worker(client.clone(), handoffs_rx.clone())
+                .instrument(tracing::info_span!("download", download_worker)),
+        )
+    }));
+
+    let (track_results, download_results) = future::join(track_workers, download_workers).await;
+
+    for result in track_results.into_iter().chain(download_results) {
+        downloaded_tracks.extend(result.unwrap());
     }
 
-    let file_stem =
-        text_utils::format_track_stem(track, track_number, track_count, is_grouped_single);
+    downloaded_tracks
+}
 
-    if !force_download {
-        let mut directory = fs::read_dir(album_path.as_path()).await.unwrap();
+#[expect(
+    clippy::too_many_arguments,
+    reason = "this function is called from a single place"
+)]
+pub async fn process_track(
+    client: Client,
+    service: Service,
+    track: &Track,
+    location: &TrackLocation,
+    tokens: &sync::Mutex<PageTokens>,
+    existing_files: ExistingFiles,
+    config: &DownloadConfig,
+    cancel: CancellationToken,
+) -> Option<ProcessedTrack> {
+    if let Err(reason) = track.availability(service) {
+        if !config.attempt_unavailable {
+            tracing::error!("skipping unavailable track {}: {reason}", track.title);
+            add_unavailable_track(track, reason);
+            return None;
+        }
+
+        tracing::warn!(
+            "track {} seems to be unavailable ({reason}), attempting anyway",
+            track.title
+        );
+    }
+
+    let file_stem = text_utils::format_track_stem(
+        track,
+        location.track_number,
+        location.track_count,
+        location.is_grouped_single,
+    );
+
+    let mut existing_path = None;
+
+    if existing_files != ExistingFiles::Overwrite {
+        let mut directory = fs::read_dir(location.directory.as_path()).await.unwrap();
 
         while let Some(entry) = directory.next_entry().await.unwrap() {
             if entry.file_type().await.unwrap().is_file()
@@ -224,167 +835,561 @@ pub async fn request_and_download_track(
                     .file_stem()
                     .is_some_and(|stem| stem.to_str().unwrap() == file_stem)
             {
-                tracing::info!("track {} is already downloaded", track.title);
-                return;
+                if existing_files == ExistingFiles::Keep {
+                    tracing::info!("track {} is already downloaded", track.title);
+                    return Some(ProcessedTrack::Existing(entry.path()));
+                }
+
+                existing_path = Some(entry.path());
+                break;
             }
         }
     }
 
-    tracing::info!("downloading track {}", track.title);
+    if let Some(existing_path) = &existing_path {
+        if !can_upgrade(existing_path, config.qualities[0]).await {
+            return Some(ProcessedTrack::Existing(existing_path.clone()));
+        }
 
-    request_track_download(
-        client,
-        track,
+        tracing::info!("checking for a better version of track {}", track.title);
+    } else {
+        tracing::info!("requesting track {}", track.title);
+    }
+
+    let (track_download, quality) =
+        request_track_download(&client, service, track, tokens, config, &cancel).await?;
+
+    Some(ProcessedTrack::Ready(TrackHandoff {
+        track_download,
+        quality,
+        directory: location.directory.clone(),
         file_stem,
-        token_expiry,
-        config,
-        album_path,
-        running,
-    )
-    .await;
+        existing_path,
+        cancel,
+    }))
 }
 
+/// requests the track download and waits until lucida processed the track
 async fn request_track_download(
-    client: Client,
+    client: &Client,
+    service: Service,
     track: &Track,
-    file_stem: String,
-    token_expiry: u64,
+    tokens: &sync::Mutex<PageTokens>,
     config: &DownloadConfig,
-    album_path: Arc<PathBuf>,
-    running: Arc<AtomicBool>,
-) {
+    cancel: &CancellationToken,
+) -> Option<(TrackDownload, Quality)> {
+    let mut processing_errors = 0;
+
     'request_track_download: loop {
-        let Some(track_download) =
-            requests::request_track_download(&client, track, token_expiry, config, running.clone())
-                .await
-        else {
-            break;
-        };
+        let (track_download, quality) =
+            request_track(client, service, track, tokens, config, cancel).await?;
 
-        let mut last_status: Option<(String, String, Instant)> = None;
+        // the time the downloads were paused for isn't counted as stuck
+        let mut last_status: Option<(ProcessingStatus, String, Duration)> = None;
 
         loop {
-            let Some(track_download) =
-                requests::track_download_status(&client, &track_download).await
-            else {
-                if !running.load(Ordering::Relaxed) {
-                    return;
+            let status = cancel
+                .run_until_cancelled(requests::track_download_status(
+                    client,
+                    &track_download,
+                    cancel,
+                ))
+                .await?;
+
+            let Some(track_download) = status else {
+                if cancel.is_cancelled() {
+                    return None;
                 }
 
                 continue 'request_track_download;
             };
 
+            let message = track_download.message.replace("{item}", &track.title);
+
             if last_status.as_ref().is_none_or(|last_status| {
-                (&track_download.status, &track_download.message)
-                    != (&last_status.0, &last_status.1)
+                (track_download.status, &message) != (last_status.0, &last_status.1)
             }) {
-                tracing::info!(
-                    "new download status: {}: {}",
-                    track_download.status,
-                    track_download.message.replace("{item}", &track.title)
-                );
-
+                tracing::info!("new download status: {}: {message}", track_download.status);
                 last_status = Some((
-                    track_download.status.clone(),
-                    track_download.message,
-                    Instant::now(),
+                    track_download.status,
+                    message.clone(),
+                    controls::running_time(),
                 ));
             } else if let Some(last_status) = last_status.as_ref()
-                && last_status.2.elapsed() >= Duration::from_secs(30)
+                && controls::running_time().saturating_sub(last_status.2)
+                    >= config.stuck_timeout(last_status.0)
             {
-                let is_running = running.load(Ordering::Relaxed);
+                let is_cancelled = cancel.is_cancelled();
 
                 tracing::warn!(
-                    "download status stuck for 30 seconds on {}: {}{}",
+                    "download status stuck for {} seconds on {}: {}{}",
+                    controls::running_time()
+                        .saturating_sub(last_status.2)
+                        .as_secs(),
                     last_status.0,
-                    last_status.1.replace("{item}", &track.title),
-                    if is_running { ", retrying" } else { "" }
+                    last_status.1,
+                    if is_cancelled { "" } else { ", retrying" }
                 );
 
-                if !is_running {
-                    return;
+                if is_cancelled {
+                    return None;
                 }
 
                 continue 'request_track_download;
             }
 
-            if track_download.status == "completed" {
-                break;
+            match track_download.status {
+                ProcessingStatus::Completed => break,
+                ProcessingStatus::Error => {
+                    processing_errors += 1;
+
+                    if processing_errors >= MAX_PROCESSING_ERRORS {
+                        tracing::error!(
+                            "giving up on track {} after {processing_errors} processing errors: {message}",
+                            track.title
+                        );
+
+                        return None;
+                    }
+
+                    if cancel.is_cancelled() {
+                        return None;
+                    }
+
+                    tracing::warn!(
+                        "processing track {} failed, retrying: {message}",
+                        track.title
+                    );
+                    continue 'request_track_download;
+                }
+                ProcessingStatus::Queued
+                | ProcessingStatus::Processing
+                | ProcessingStatus::Uploading
+                | ProcessingStatus::Unknown => {
+                    cancel
+                        .run_until_cancelled(time::sleep(Duration::from_secs(1)))
+                        .await?;
+                }
+            }
+        }
+
+        return Some((track_download, quality));
+    }
+}
+
+/// requests the track download, refreshing the tokens of the page when they
+/// expire
+async fn request_track(
+    client: &Client,
+    service: Service,
+    track: &Track,
+    tokens: &sync::Mutex<PageTokens>,
+    config: &DownloadConfig,
+    cancel: &CancellationToken,
+) -> Option<(TrackDownload, Quality)> {
+    let mut stale_expiry = None;
+    let mut token_refreshes = 0;
+
+    loop {
+        let (expiry, primary, secondary) =
+            track_tokens(client, track, tokens, stale_expiry, config, cancel).await?;
+
+        match requests::request_track_download(
+            client,
+            service,
+            track,
+            Token {
+                expiry,
+                primary: primary.as_deref(),
+                secondary: secondary.as_deref(),
+            },
+            config,
+            cancel.clone(),
+        )
+        .await
+        {
+            Ok(track_download) => break Some(track_download),
+            Err(RequestTrackError::Stopped) => break None,
+            Err(RequestTrackError::Unavailable(reason)) => {
+                tracing::error!("skipping unavailable track {}: {reason}", track.title);
+                add_unavailable_track(track, reason);
+                break None;
             }
+            Err(RequestTrackError::TokenExpired) => {
+                if token_refreshes == MAX_TOKEN_REFRESHES {
+                    tracing::error!(
+                        "giving up on track {}, lucida keeps rejecting its tokens",
+                        track.title
+                    );
 
-            time::sleep(Duration::from_secs(1)).await;
+                    break None;
+                }
+
+                tracing::warn!("tokens expired when requesting track {}", track.title);
+                stale_expiry = Some(expiry);
+
+                // fresh tokens are tried right away, lucida rejecting them
+                // again is waited out
+                if token_refreshes > 0
+                    && cancel
+                        .run_until_cancelled(time::sleep(Duration::from_secs(5)))
+                        .await
+                        .is_none()
+                {
+                    break None;
+                }
+
+                token_refreshes += 1;
+            }
         }
+    }
+}
 
-        download_track(client, track_download, album_path, file_stem, running).await;
+/// returns the expiry and the tokens for the track. the tokens of the page are
+/// refreshed first when they expired or lucida rejected the ones with
+/// `stale_expiry`, unless another track worker refreshed them already
+async fn track_tokens(
+    client: &Client,
+    track: &Track,
+    tokens: &sync::Mutex<PageTokens>,
+    stale_expiry: Option<u64>,
+    config: &DownloadConfig,
+    cancel: &CancellationToken,
+) -> Option<(u64, Option<String>, Option<String>)> {
+    let mut tokens = tokens.lock().await;
 
-        break;
+    if stale_expiry == Some(tokens.expiry) || is_token_expired(tokens.expiry) {
+        tracing::info!("refreshing tokens by resolving {} again", tokens.url);
+
+        let page_data =
+            resolve_page(client, &tokens.url, config, tokens.expand_tracks, cancel).await?;
+
+        tokens.expiry = page_data.token_expiry;
+
+        if let Ok(album) = AlbumInfo::new(page_data.info, page_data.token) {
+            tokens.tracks = album
+                .tracks
+                .into_iter()
+                .map(|(_, track)| (track.url, (track.csrf, track.csrf_fallback)))
+                .collect();
+        }
     }
+
+    let (primary, secondary) = tokens
+        .tracks
+        .get(&track.url)
+        .cloned()
+        .unwrap_or_else(|| (track.csrf.clone(), track.csrf_fallback.clone()));
+
+    Some((tokens.expiry, primary, secondary))
+}
+
+/// lucida sends the expiry as a unix timestamp, accept both seconds and
+/// milliseconds. tokens are treated as expired a minute early
+fn is_token_expired(expiry: u64) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    let expiry = if expiry > 100_000_000_000 {
+        Duration::from_millis(expiry)
+    } else {
+        Duration::from_secs(expiry)
+    };
+
+    expiry < now + Duration::from_mins(1)
 }
 
-async fn download_track(
+/// downloads the track. when `existing_path` is set, the downloaded file only
+/// replaces the existing one if it's in a better format, and the download stops
+/// as soon as its format is known not to be
+pub async fn download_track(
     client: Client,
-    track_download: TrackDownload,
-    album_path: Arc<PathBuf>,
-    file_stem: String,
-    running: Arc<AtomicBool>,
-) {
+    handoff: TrackHandoff,
+) -> Option<(PathBuf, Option<Quality>)> {
+    let TrackHandoff {
+        track_download,
+        quality,
+        directory: album_path,
+        file_stem,
+        existing_path,
+        cancel,
+    } = handoff;
+
+    let mut failed_verifications = 0;
+
+    let existing_format = OptionFuture::from(existing_path.as_deref().map(read_file_format))
+        .await
+        .flatten();
+
+    // part file, length and verifier of an interrupted download, to resume it
+    let mut interrupted_download: Option<(PathBuf, u64, AudioVerifier)> = None;
+
     'download_track: loop {
-        let Some((mut rx, mime_type)) = requests::download_track(&client, &track_download).await
+        let offset = interrupted_download
+            .as_ref()
+            .map_or(0, |download| download.1);
+
+        let Some(TrackStream {
+            mut chunks,
+            mime_type,
+            content_length,
+            is_resumed,
+        }) = requests::download_track(&client, &track_download, offset, &cancel).await
         else {
-            if !running.load(Ordering::Relaxed) {
-                return;
+            if cancel.is_cancelled() {
+                return None;
             }
 
             continue;
         };
 
-        let file_extension = match mime_type
-            .split_once(';')
-            .map_or(mime_type.as_str(), |(mime_type, _)| mime_type)
-        {
-            "audio/flac" => "flac",
-            "audio/mpeg" => "mp3",
-            "audio/mp4" => "m4a",
-            _ => panic!("unsupported mime type {mime_type}"),
+        let mime_type_extension = integrity::mime_type_extension(&mime_type);
+
+        let part_path = album_path.join(format!(
+            "{file_stem}.{}.part",
+            mime_type_extension.unwrap_or(integrity::GENERIC_FILE_EXTENSION)
+        ));
+
+        let Some((mut file, mut downloaded_length, mut verifier)) = open_part_file(
+            &part_path,
+            mime_type_extension,
+            interrupted_download.take(),
+            is_resumed,
+        )
+        .await
+        else {
+            continue;
         };
 
-        let file_name = format!("{file_stem}.{file_extension}");
-        let part_path = album_path.join(format!("{file_name}.part"));
-        let mut file = BufWriter::new(File::create(&part_path).await.unwrap());
+        let mut verification = Ok(());
+        let mut is_compared = existing_path.is_none();
 
-        while let Some(result) = rx.recv().await {
-            match result {
-                Ok(chunk) => file.write_all(&chunk).await.unwrap(),
-                Err(()) => continue 'download_track,
+        while let Some(result) = chunks.recv().await {
+            let Ok(chunk) = result else {
+                file.flush().await.unwrap();
+                interrupted_download = Some((part_path, downloaded_length, verifier));
+                continue 'download_track;
+            };
+
+            file.write_all(&chunk).await.unwrap();
+            downloaded_length += chunk.len() as u64;
+
+            // the rest of a corrupted file isn't downloaded
+            verification = verifier.update(&chunk);
+
+            if verification.is_err() {
+                break;
+            }
+
+            // the rest of a file that isn't an upgrade isn't downloaded
+            if !is_compared && let Some(format) = verifier.format() {
+                if !is_upgrade(existing_format.as_ref(), Some(format), &file_stem) {
+                    drop(file);
+                    discard_part_file(&part_path).await;
+                    return existing_path.map(|existing_path| (existing_path, None));
+                }
+
+                is_compared = true;
             }
         }
 
-        fs::rename(part_path, album_path.join(file_name))
-            .await
-            .unwrap();
+        file.flush().await.unwrap();
+        drop(file);
 
-        break;
+        // the stream ends early when the track is cancelled
+        if cancel.is_cancelled() {
+            return None;
+        }
+
+        let (file_extension, format) = match verification
+            .and_then(|()| verify_track(verifier, downloaded_length, content_length))
+        {
+            Ok(verified_track) => verified_track,
+            Err(err) => {
+                failed_verifications += 1;
+
+                if failed_verifications >= MAX_FAILED_VERIFICATIONS {
+                    tracing::error!("giving up on {file_stem} after failed verification: {err}");
+                    discard_part_file(&part_path).await;
+                    return None;
+                }
+
+                tracing::warn!("{file_stem} failed verification, retrying: {err}");
+                continue;
+            }
+        };
+
+        let file_name = track_file_name(&file_stem, file_extension, &mime_type);
+
+        // the format of some files is only known at their end
+        if !is_compared && !is_upgrade(existing_format.as_ref(), format.as_ref(), &file_name) {
+            discard_part_file(&part_path).await;
+            return existing_path.map(|existing_path| (existing_path, None));
+        }
+
+        let path = album_path.join(file_name);
+        save_track_file(&part_path, &path, existing_path.as_deref()).await;
+
+        break Some((path, Some(quality)));
     }
 }
 
+fn track_file_name(file_stem: &str, file_extension: Option<&str>, mime_type: &str) -> String {
+    let file_extension = file_extension.unwrap_or_else(|| {
+        tracing::warn!(
+            "unknown type {mime_type} of {file_stem}, saving it with the .{} extension",
+            integrity::GENERIC_FILE_EXTENSION
+        );
+
+        integrity::GENERIC_FILE_EXTENSION
+    });
+
+    format!("{file_stem}.{file_extension}")
+}
+
+async fn discard_part_file(part_path: &Path) {
+    fs::remove_file(part_path).await.unwrap();
+    cleanups::forget_part_file(part_path);
+}
+
+/// moves the downloaded part file in place of the existing file of the track
+async fn save_track_file(part_path: &Path, path: &Path, existing_path: Option<&Path>) {
+    // renaming replaces a file with the same name atomically, a file with a
+    // different extension is only removed once the new one is in place
+    fs::rename(part_path, path).await.unwrap();
+    cleanups::forget_part_file(part_path);
+
+    if let Some(existing_path) = existing_path
+        && existing_path != path
+    {
+        fs::remove_file(existing_path).await.unwrap();
+        manifests::forget_file(existing_path).await;
+    }
+}
+
+/// opens the part file, appending to the one of the interrupted download when
+/// lucida resumed it. returns the file, its length and its verifier, or `None`
+/// when the download has to be requested again from the start
+async fn open_part_file(
+    part_path: &Path,
+    mime_type_extension: Option<&'static str>,
+    interrupted_download: Option<(PathBuf, u64, AudioVerifier)>,
+    is_resumed: bool,
+) -> Option<(BufWriter<File>, u64, AudioVerifier)> {
+    if let Some((interrupted_path, length, verifier)) = interrupted_download {
+        if is_resumed && interrupted_path == part_path {
+            tracing::info!("resuming {} at {length} bytes", part_path.display());
+
+            let file = OpenOptions::new()
+                .append(true)
+                .open(part_path)
+                .await
+                .unwrap();
+
+            return Some((BufWriter::new(file), length, verifier));
+        }
+
+        if interrupted_path != part_path {
+            fs::remove_file(&interrupted_path).await.unwrap();
+            cleanups::forget_part_file(&interrupted_path);
+        }
+
+        // the rest of a file of another type can't be appended to the
+        // interrupted one
+        if is_resumed {
+            tracing::warn!(
+                "lucida resumed {} with a different type, downloading it again",
+                part_path.display()
+            );
+
+            return None;
+        }
+    }
+
+    cleanups::add_part_file(part_path);
+
+    Some((
+        BufWriter::new(File::create(part_path).await.unwrap()),
+        0,
+        AudioVerifier::new(mime_type_extension),
+    ))
+}
+
+/// whether lucida may provide a better format than the one of the existing
+/// file in the quality, which is only known for downscaled qualities
+async fn can_upgrade(existing_path: &Path, quality: Quality) -> bool {
+    let Some(best_format) = quality.best_format() else {
+        return true;
+    };
+
+    let Some(existing_format) = read_file_format(existing_path).await else {
+        return true;
+    };
+
+    if best_format.is_better_than(&existing_format) {
+        return true;
+    }
+
+    tracing::info!(
+        "keeping {} in {existing_format}, lucida doesn't provide a better format in {} quality",
+        existing_path.display(),
+        quality.downscale()
+    );
+
+    false
+}
+
+/// whether the downloaded file is in a better format than the existing one.
+/// an existing file with an unknown format is kept
+fn is_upgrade(
+    existing_format: Option<&AudioFormat>,
+    format: Option<&AudioFormat>,
+    file_name: &str,
+) -> bool {
+    match (existing_format, format) {
+        (Some(existing_format), Some(format)) if format.is_better_than(existing_format) => {
+            tracing::info!("upgrading {file_name} from {existing_format} to {format}");
+            true
+        }
+        (Some(existing_format), Some(format)) => {
+            tracing::info!("keeping {file_name} in {existing_format}, lucida provides {format}");
+            false
+        }
+        _ => {
+            tracing::warn!("cannot compare the format of the existing {file_name}, keeping it");
+            false
+        }
+    }
+}
+
+/// finishes checking the downloaded file and returns its extension, guessed
+/// from its content when the MIME type is unknown, and its format
+fn verify_track(
+    verifier: AudioVerifier,
+    downloaded_length: u64,
+    content_length: Option<u64>,
+) -> Result<(Option<&'static str>, Option<AudioFormat>), String> {
+    if let Some(content_length) = content_length
+        && downloaded_length != content_length
+    {
+        return Err(format!(
+            "received {downloaded_length} bytes, expected {content_length}"
+        ));
+    }
+
+    verifier.finish()
+}
+
 pub async fn download_album_cover(
     client: Client,
     title: &str,
     service: Service,
     url: &str,
-    force_download: bool,
+    existing_files: ExistingFiles,
     album_path: &Path,
-    running: Arc<AtomicBool>,
+    cancel: CancellationToken,
 ) {
     let cover_path = album_path.join("cover.jpg");
 
-    if !force_download && cover_path.exists() {
-        tracing::info!("{title} album cover is already downloaded");
-        return;
-    }
-
-    tracing::info!("downloading {title} album cover");
-
     let url = match service {
         Service::Qobuz => {
             let stripped_url = url.strip_suffix(".jpg").unwrap();
@@ -394,21 +1399,30 @@ pub async fn download_album_cover(
         Service::Tidal | Service::Soundcloud | Service::Amazon => Cow::Borrowed(url),
     };
 
+    if existing_files != ExistingFiles::Overwrite && cover_path.exists() {
+        tracing::info!("{title} album cover is already downloaded");
+        manifests::record_files(service, vec![(cover_path, url.into_owned(), None)]).await;
+        return;
+    }
+
+    tracing::info!("downloading {title} album cover");
+
     let part_path = album_path.join("cover.jpg.part");
 
     'download_album_cover: loop {
-        let Some(mut rx) = requests::download_album_cover(&client, &url, running.clone()).await
+        let Some(mut rx) = requests::download_album_cover(&client, &url, cancel.clone()).await
         else {
             return;
         };
 
+        cleanups::add_part_file(&part_path);
         let mut file = BufWriter::new(File::create(&part_path).await.unwrap());
 
         while let Some(chunk) = rx.recv().await {
             if let Ok(chunk) = chunk {
                 file.write_all(&chunk).await.unwrap();
             } else {
-                if !running.load(Ordering::Relaxed) {
+                if cancel.is_cancelled() {
                     return;
                 }
 
@@ -417,8 +1431,16 @@ pub async fn download_album_cover(
         }
 
         file.flush().await.unwrap();
+
+        // the stream ends early when the album is cancelled
+        if cancel.is_cancelled() {
+            return;
+        }
+
         break;
     }
 
-    fs::rename(part_path, cover_path).await.unwrap();
+    fs::rename(&part_path, &cover_path).await.unwrap();
+    cleanups::forget_part_file(&part_path);
+    manifests::record_files(service, vec![(cover_path, url.into_owned(), None)]).await;
 }
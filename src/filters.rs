@@ -0,0 +1,129 @@
+use std::ops::RangeInclusive;
+
+use regex::Regex;
+
+use crate::models::Track;
+
+#[derive(Default)]
+pub struct TrackFilter {
+    /// only the tracks with these URLs are selected when it isn't empty
+    pub track_urls: Vec<String>,
+    pub track_numbers: Vec<RangeInclusive<u32>>,
+    pub include_titles: Vec<Regex>,
+    pub exclude_titles: Vec<Regex>,
+    pub include_artists: Vec<Regex>,
+    pub exclude_artists: Vec<Regex>,
+}
+
+impl TrackFilter {
+    /// returns the reason why the track should be skipped
+    pub fn rejection(&self, track_number: Option<u32>, track: &Track) -> Option<&'static str> {
+        if !self.track_urls.is_empty() && !self.track_urls.contains(&track.url) {
+            return Some("track URL not selected");
+        }
+
+        if let Some(track_number) = track_number
+            && !self.track_numbers.is_empty()
+            && !self
+                .track_numbers
+                .iter()
+                .any(|range| range.contains(&track_number))
+        {
+            return Some("track number not selected");
+        }
+
+        if !self.include_titles.is_empty()
+            && !self
+                .include_titles
+                .iter()
+                .any(|regex| regex.is_match(&track.title))
+        {
+            return Some("title not included");
+        }
+
+        if self
+            .exclude_titles
+            .iter()
+            .any(|regex| regex.is_match(&track.title))
+        {
+            return Some("title excluded");
+        }
+
+        if !self.include_artists.is_empty()
+            && !track.artists.iter().any(|artist| {
+                self.include_artists
+                    .iter()
+                    .any(|regex| regex.is_match(&artist.name))
+            })
+        {
+            return Some("artist not included");
+        }
+
+        if track.artists.iter().any(|artist| {
+            self.exclude_artists
+                .iter()
+                .any(|regex| regex.is_match(&artist.name))
+        }) {
+            return Some("artist excluded");
+        }
+
+        None
+    }
+}
+
+/// parses track number ranges like `1-3,7`
+pub fn parse_track_ranges(value: &str) -> Result<Vec<RangeInclusive<u32>>, String> {
+    value
+        .split(',')
+        .map(|range| {
+            let range = range.trim();
+
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+
+            let parse = |number: &str| {
+                number
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|err| format!("invalid track number {number:?}: {err}"))
+            };
+
+            let (start, end) = (parse(start)?, parse(end)?);
+
+            if start > end {
+                return Err(format!("invalid track range {range:?}"));
+            }
+
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_ranges() {
+        assert_eq!(parse_track_ranges("7"), Ok(vec![7..=7]));
+        assert_eq!(parse_track_ranges("1-3,7"), Ok(vec![1..=3, 7..=7]));
+        assert_eq!(parse_track_ranges(" 1 - 3 , 5-5 "), Ok(vec![1..=3, 5..=5]));
+    }
+
+    #[test]
+    fn invalid_track_ranges() {
+        for value in [
+            "",
+            "a",
+            "1-",
+            "-3",
+            "1-3-5",
+            "1,,3",
+            "3-1",
+            "-1",
+            "1.5",
+            "99999999999",
+        ] {
+            assert!(parse_track_ranges(value).is_err(), "{value:?}");
+        }
+    }
+}
@@ -0,0 +1,1992 @@
+use std::fmt::{self, Display, Formatter};
+use std::{iter, mem};
+
+/// extensions of the audio files tracks are saved with
+pub const AUDIO_FILE_EXTENSIONS: [&str; 7] = ["flac", "mp3", "m4a", "opus", "ogg", "aac", "wav"];
+
+/// extension of files with an unknown type
+pub const GENERIC_FILE_EXTENSION: &str = "bin";
+
+/// bytes needed to tell the types of files apart by their magic bytes
+const SNIFF_LENGTH: usize = 36;
+/// the moov box of a track is kept in memory to read the format from it
+const MAX_MP4_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+const FLAC_STREAMINFO_LENGTH: usize = 34;
+/// longest possible frame header, including the CRC-8
+const FLAC_MAX_FRAME_HEADER_LENGTH: usize = 16;
+const CRC16_TABLE: [u16; 256] = crc16_table();
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// returns the extension for files with the MIME type
+pub fn mime_type_extension(mime_type: &str) -> Option<&'static str> {
+    let (essence, parameters) = mime_type.split_once(';').unwrap_or((mime_type, ""));
+
+    match essence.trim().to_ascii_lowercase().as_str() {
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" => Some("m4a"),
+        "audio/ogg" | "application/ogg" if parameters.contains("opus") => Some("opus"),
+        "audio/opus" => Some("opus"),
+        "audio/ogg" | "application/ogg" | "audio/vorbis" => Some("ogg"),
+        "audio/aac" | "audio/aacp" | "audio/x-aac" => Some("aac"),
+        "audio/wav" | "audio/wave" | "audio/x-wav" | "audio/vnd.wave" => Some("wav"),
+        _ => None,
+    }
+}
+
+/// checks that a downloaded file is a complete and uncorrupted audio file and
+/// reads its format while the file is fed to it in chunks. only the part of
+/// the file that is yet to be parsed is kept, audio data is skipped over
+pub struct AudioVerifier {
+    parser: Parser,
+    file_extension: Option<&'static str>,
+    format: Option<AudioFormat>,
+    /// received bytes that weren't parsed yet
+    buffer: Vec<u8>,
+    /// offset of the buffer in the file
+    offset: u64,
+    /// bytes the parser skips over
+    skip: u64,
+}
+
+/// what a parser did with the data it was given
+enum Step<T = u64> {
+    /// the data ends before the part being parsed does
+    NeedMore,
+    /// the result, or the amount of parsed bytes. it may be more than the
+    /// parser was given, to skip over data that isn't checked
+    Parsed(T),
+}
+
+enum Parser {
+    /// the type of the file is guessed from its magic bytes
+    Sniff,
+    Flac(FlacParser),
+    Mp3(Mp3Parser),
+    Mp4(Mp4Parser),
+    Ogg(OggParser),
+    Adts(AdtsParser),
+    Wav(WavParser),
+    /// files of unknown types aren't checked
+    Unchecked,
+}
+
+impl AudioVerifier {
+    /// the type of the file is guessed from its content when the extension is
+    /// unknown
+    pub fn new(file_extension: Option<&'static str>) -> Self {
+        Self {
+            parser: file_extension.map_or(Parser::Sniff, Parser::new),
+            file_extension,
+            format: None,
+            buffer: Vec::new(),
+            offset: 0,
+            skip: 0,
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), String> {
+        let skipped = usize::try_from(self.skip).map_or(chunk.len(), |skip| skip.min(chunk.len()));
+
+        self.skip -= skipped as u64;
+        self.offset += skipped as u64;
+        self.buffer.extend_from_slice(&chunk[skipped..]);
+
+        self.parse(false)
+    }
+
+    /// checks the end of the file and returns its extension, guessed from its
+    /// content when it was unknown, and its format
+    pub fn finish(mut self) -> Result<(Option<&'static str>, Option<AudioFormat>), String> {
+        self.parse(true)?;
+
+        if self.skip > 0 {
+            return Err(format!("file ends {} bytes early", self.skip));
+        }
+
+        match &self.parser {
+            Parser::Flac(parser) => parser.finish()?,
+            Parser::Mp3(parser) => parser.finish(self.offset)?,
+            Parser::Mp4(parser) => parser.finish()?,
+            Parser::Ogg(parser) => parser.finish()?,
+            Parser::Adts(parser) => parser.finish()?,
+            Parser::Wav(parser) => parser.finish(self.offset)?,
+            Parser::Sniff | Parser::Unchecked => {}
+        }
+
+        Ok((self.file_extension, self.format))
+    }
+
+    /// the format, once the headers holding it are parsed
+    pub const fn format(&self) -> Option<&AudioFormat> {
+        self.format.as_ref()
+    }
+
+    pub fn into_format(self) -> Option<AudioFormat> {
+        self.format
+    }
+
+    /// returns the bytes the parser skips over, so a file can be seeked past
+    /// them instead of reading them
+    pub fn take_skip(&mut self) -> u64 {
+        self.offset = self.offset.saturating_add(self.skip);
+        mem::take(&mut self.skip)
+    }
+
+    fn parse(&mut self, is_end: bool) -> Result<(), String> {
+        let mut position = 0;
+
+        while position < self.buffer.len() {
+            let data = &self.buffer[position..];
+            let offset = self.offset + position as u64;
+
+            let step = match &mut self.parser {
+                Parser::Sniff => match sniff_extension(data, is_end) {
+                    Step::NeedMore => Step::NeedMore,
+                    Step::Parsed(file_extension) => {
+                        self.file_extension = file_extension;
+                        self.parser = file_extension.map_or(Parser::Unchecked, Parser::new);
+                        Step::Parsed(0)
+                    }
+                },
+                Parser::Flac(parser) => parser.parse(data, offset, is_end, &mut self.format)?,
+                Parser::Mp3(parser) => parser.parse(data, offset, is_end, &mut self.format)?,
+                Parser::Mp4(parser) => parser.parse(data, offset, is_end, &mut self.format)?,
+                Parser::Ogg(parser) => parser.parse(data, offset, &mut self.format)?,
+                Parser::Adts(parser) => parser.parse(data, offset, &mut self.format)?,
+                Parser::Wav(parser) => parser.parse(data, &mut self.format)?,
+                Parser::Unchecked => Step::Parsed(data.len() as u64),
+            };
+
+            match step {
+                Step::NeedMore if is_end => {
+                    return Err(format!("file is truncated at offset {offset}"));
+                }
+                Step::NeedMore => break,
+                Step::Parsed(length) => {
+                    let available = (self.buffer.len() - position) as u64;
+
+                    if length > available {
+                        self.skip = length - available;
+                        position = self.buffer.len();
+                    } else {
+                        position += usize::try_from(length).unwrap();
+                    }
+                }
+            }
+        }
+
+        self.buffer.drain(..position);
+        self.offset += position as u64;
+
+        Ok(())
+    }
+}
+
+impl Parser {
+    fn new(file_extension: &str) -> Self {
+        match file_extension {
+            "flac" => Self::Flac(FlacParser::default()),
+            "mp3" => Self::Mp3(Mp3Parser::default()),
+            "m4a" => Self::Mp4(Mp4Parser::default()),
+            "opus" | "ogg" => Self::Ogg(OggParser::default()),
+            "aac" => Self::Adts(AdtsParser::default()),
+            "wav" => Self::Wav(WavParser::default()),
+            _ => Self::Unchecked,
+        }
+    }
+}
+
+/// guesses the extension from the magic bytes at the start of the file
+fn sniff_extension(data: &[u8], is_end: bool) -> Step<Option<&'static str>> {
+    if data.len() < SNIFF_LENGTH && !is_end {
+        return Step::NeedMore;
+    }
+
+    Step::Parsed(if data.starts_with(b"fLaC") {
+        Some("flac")
+    } else if data.get(4..8) == Some(b"ftyp") {
+        Some("m4a")
+    } else if data.starts_with(b"OggS") {
+        Some(if data.get(28..36) == Some(b"OpusHead") {
+            "opus"
+        } else {
+            "ogg"
+        })
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+        Some("wav")
+    } else {
+        // the frames follow the ID3 tag, which is only incomplete at the end
+        // of a file that is too short
+        let Step::Parsed(position) = id3_tag_length(data) else {
+            return Step::Parsed(None);
+        };
+
+        let Some(frame_sync) = data.get(position..position + 2) else {
+            return if is_end {
+                Step::Parsed(None)
+            } else {
+                Step::NeedMore
+            };
+        };
+
+        match frame_sync {
+            // ADTS headers have the layer bits cleared
+            [0xff, byte] if byte & 0xf6 == 0xf0 => Some("aac"),
+            [0xff, byte] if byte & 0xe0 == 0xe0 => Some("mp3"),
+            _ => None,
+        }
+    })
+}
+
+/// returns the length of the ID3 tag at the start of the file, if it has one
+fn id3_tag_length(data: &[u8]) -> Step<usize> {
+    if !data.starts_with(b"ID3") {
+        return if data.len() < 3 && b"ID3".starts_with(data) {
+            Step::NeedMore
+        } else {
+            Step::Parsed(0)
+        };
+    }
+
+    let Some(header) = data.get(..10) else {
+        return Step::NeedMore;
+    };
+
+    let size = header[6..10]
+        .iter()
+        .fold(0, |size, &byte| (size << 7) | usize::from(byte & 0x7f));
+
+    let has_footer = header[5] & 0x10 != 0;
+    Step::Parsed(10 + size + if has_footer { 10 } else { 0 })
+}
+
+pub enum AudioFormat {
+    Flac {
+        bits_per_sample: u8,
+        sample_rate: u32,
+    },
+    Alac,
+    Wav {
+        bits_per_sample: u8,
+        sample_rate: u32,
+    },
+    /// the bitrate is unknown for VBR files
+    Mp3 {
+        bitrate: Option<u32>,
+    },
+    Aac {
+        bitrate: Option<u32>,
+    },
+    Vorbis {
+        bitrate: Option<u32>,
+    },
+    Opus,
+}
+
+impl AudioFormat {
+    /// whether the format is strictly better than the other one. formats that
+    /// cannot be compared, like FLAC and ALAC, are never better
+    pub fn is_better_than(&self, other: &Self) -> bool {
+        if self.is_lossless() != other.is_lossless() {
+            return self.is_lossless();
+        }
+
+        if let (Some(resolution), Some(other_resolution)) = (self.resolution(), other.resolution())
+        {
+            return resolution.0 >= other_resolution.0
+                && resolution.1 >= other_resolution.1
+                && resolution != other_resolution;
+        }
+
+        matches!(
+            (self.weighted_bitrate(), other.weighted_bitrate()),
+            (Some(bitrate), Some(other_bitrate)) if bitrate > other_bitrate
+        )
+    }
+
+    const fn is_lossless(&self) -> bool {
+        matches!(self, Self::Flac { .. } | Self::Alac | Self::Wav { .. })
+    }
+
+    /// bits per sample and sample rate of lossless formats
+    const fn resolution(&self) -> Option<(u8, u32)> {
+        match *self {
+            Self::Flac {
+                bits_per_sample,
+                sample_rate,
+            }
+            | Self::Wav {
+                bits_per_sample,
+                sample_rate,
+            } => Some((bits_per_sample, sample_rate)),
+            _ => None,
+        }
+    }
+
+    /// bitrate of lossy formats, weighted by the efficiency of the codec so
+    /// formats with different codecs can be compared. AAC and Vorbis sound about
+    /// as good as MP3 at 70% of its bitrate
+    fn weighted_bitrate(&self) -> Option<u64> {
+        match *self {
+            Self::Mp3 { bitrate } => bitrate.map(u64::from),
+            Self::Aac { bitrate } | Self::Vorbis { bitrate } => {
+                bitrate.map(|bitrate| u64::from(bitrate) * 10 / 7)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// formats the audio format for directory names, e.g. "FLAC 24-96" or "MP3 320"
+impl Display for AudioFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Flac {
+                bits_per_sample,
+                sample_rate,
+            } => write!(
+                f,
+                "FLAC {bits_per_sample}-{}",
+                f64::from(*sample_rate) / 1000.0
+            ),
+            Self::Alac => write!(f, "ALAC"),
+            Self::Wav {
+                bits_per_sample,
+                sample_rate,
+            } => write!(
+                f,
+                "WAV {bits_per_sample}-{}",
+                f64::from(*sample_rate) / 1000.0
+            ),
+            Self::Mp3 {
+                bitrate: Some(bitrate),
+            } => write!(f, "MP3 {}", bitrate / 1000),
+            Self::Mp3 { bitrate: None } => write!(f, "MP3 VBR"),
+            Self::Aac {
+                bitrate: Some(bitrate),
+            } => write!(f, "AAC {}", bitrate.div_ceil(1000)),
+            Self::Aac { bitrate: None } => write!(f, "AAC"),
+            Self::Vorbis {
+                bitrate: Some(bitrate),
+            } => write!(f, "VORBIS {}", bitrate.div_ceil(1000)),
+            Self::Vorbis { bitrate: None } => write!(f, "VORBIS"),
+            Self::Opus => write!(f, "OPUS"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FlacParser {
+    state: FlacState,
+    total_samples: u64,
+    samples: u64,
+    frame_count: u64,
+    /// block size and CRC-16 of the frame being parsed, and how much of it was
+    /// scanned for the header of the next frame
+    frame: Option<(u32, u16, usize)>,
+}
+
+#[derive(Default, Clone, Copy)]
+enum FlacState {
+    #[default]
+    Marker,
+    StreamInfo,
+    MetadataBlock,
+    Frames,
+}
+
+struct StreamInfo {
+    sample_rate: u32,
+    bits_per_sample: u8,
+    total_samples: u64,
+}
+
+impl FlacParser {
+    fn parse(
+        &mut self,
+        data: &[u8],
+        offset: u64,
+        is_end: bool,
+        format: &mut Option<AudioFormat>,
+    ) -> Result<Step, String> {
+        match self.state {
+            FlacState::Marker => {
+                let Some(marker) = data.get(..4) else {
+                    return Ok(Step::NeedMore);
+                };
+
+                if marker != b"fLaC" {
+                    return Err("missing fLaC marker".into());
+                }
+
+                self.state = FlacState::StreamInfo;
+                Ok(Step::Parsed(4))
+            }
+            FlacState::StreamInfo => {
+                let Some(block) = data.get(..4 + FLAC_STREAMINFO_LENGTH) else {
+                    return Ok(Step::NeedMore);
+                };
+
+                let stream_info = parse_flac_stream_info(block)?;
+
+                *format = Some(AudioFormat::Flac {
+                    bits_per_sample: stream_info.bits_per_sample,
+                    sample_rate: stream_info.sample_rate,
+                });
+
+                self.total_samples = stream_info.total_samples;
+                self.state = next_flac_state(block[0]);
+
+                Ok(Step::Parsed(4 + FLAC_STREAMINFO_LENGTH as u64))
+            }
+            FlacState::MetadataBlock => {
+                let Some(&[block_type, a, b, c]) = data.get(..4) else {
+                    return Ok(Step::NeedMore);
+                };
+
+                self.state = next_flac_state(block_type);
+                Ok(Step::Parsed(
+                    4 + u64::from(u32::from_be_bytes([0, a, b, c])),
+                ))
+            }
+            FlacState::Frames => self.parse_frame(data, offset, is_end),
+        }
+    }
+
+    /// finds the end of the frame by looking for the next frame header at
+    /// which the CRC-16 of the preceding bytes checks out
+    fn parse_frame(&mut self, data: &[u8], offset: u64, is_end: bool) -> Result<Step, String> {
+        if data.len() < FLAC_MAX_FRAME_HEADER_LENGTH && !is_end {
+            return Ok(Step::NeedMore);
+        }
+
+        let (block_size, mut crc, scanned) = match self.frame {
+            Some(frame) => frame,
+            None => (
+                parse_flac_frame_header(data)
+                    .ok_or_else(|| format!("invalid frame header at offset {offset}"))?,
+                0,
+                0,
+            ),
+        };
+
+        for (position, &byte) in data.iter().enumerate().skip(scanned) {
+            if position > 2 && crc == 0 && byte == 0xff {
+                // the header of the next frame may not be complete yet
+                if data.len() - position < FLAC_MAX_FRAME_HEADER_LENGTH && !is_end {
+                    self.frame = Some((block_size, crc, position));
+                    return Ok(Step::NeedMore);
+                }
+
+                if parse_flac_frame_header(&data[position..]).is_some() {
+                    self.end_frame(block_size);
+                    return Ok(Step::Parsed(position as u64));
+                }
+            }
+
+            crc = crc16_update(crc, byte);
+        }
+
+        if !is_end {
+            self.frame = Some((block_size, crc, data.len()));
+            return Ok(Step::NeedMore);
+        }
+
+        if crc != 0 {
+            return Err(format!("frame at offset {offset} failed the CRC check"));
+        }
+
+        self.end_frame(block_size);
+        Ok(Step::Parsed(data.len() as u64))
+    }
+
+    const fn end_frame(&mut self, block_size: u32) {
+        self.samples += block_size as u64;
+        self.frame_count += 1;
+        self.frame = None;
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if self.frame_count == 0 {
+            return Err("file has no audio frames".into());
+        }
+
+        if self.total_samples != 0 && self.samples != self.total_samples {
+            return Err(format!(
+                "frames contain {} samples, STREAMINFO declares {}",
+                self.samples, self.total_samples
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// the frames follow the last metadata block
+const fn next_flac_state(block_type: u8) -> FlacState {
+    if block_type & 0x80 == 0 {
+        FlacState::MetadataBlock
+    } else {
+        FlacState::Frames
+    }
+}
+
+fn parse_flac_stream_info(block: &[u8]) -> Result<StreamInfo, String> {
+    if block[0] & 0x7f != 0
+        || u32::from_be_bytes([0, block[1], block[2], block[3]]) as usize != FLAC_STREAMINFO_LENGTH
+    {
+        return Err("first metadata block is not STREAMINFO".into());
+    }
+
+    let packed = u64::from_be_bytes(block[14..22].try_into().unwrap());
+
+    let stream_info = StreamInfo {
+        sample_rate: (packed >> 44) as u32,
+        bits_per_sample: ((packed >> 36) & 0x1f) as u8 + 1,
+        total_samples: packed & 0x000f_ffff_ffff,
+    };
+
+    if stream_info.sample_rate == 0 {
+        return Err("STREAMINFO has an invalid sample rate".into());
+    }
+
+    Ok(stream_info)
+}
+
+/// returns the block size of the frame if the frame header is valid
+fn parse_flac_frame_header(data: &[u8]) -> Option<u32> {
+    if data.len() < 6 || data[0] != 0xff || data[1] & 0xfe != 0xf8 {
+        return None;
+    }
+
+    let block_size_bits = data[2] >> 4;
+    let sample_rate_bits = data[2] & 0x0f;
+
+    if block_size_bits == 0
+        || sample_rate_bits == 0x0f
+        || data[3] >> 4 > 0x0a
+        || data[3] & 0x01 != 0
+    {
+        return None;
+    }
+
+    let coded_number_length = match data[4].leading_ones() {
+        0 => 1,
+        length @ 2..=7 => length as usize,
+        _ => return None,
+    };
+
+    let mut length = 4 + coded_number_length;
+
+    let block_size = match block_size_bits {
+        0x01 => 192,
+        0x02..=0x05 => 576 << (block_size_bits - 2),
+        0x06 => {
+            length += 1;
+            u32::from(*data.get(length - 1)?) + 1
+        }
+        0x07 => {
+            length += 2;
+            u32::from(u16::from_be_bytes([
+                *data.get(length - 2)?,
+                *data.get(length - 1)?,
+            ])) + 1
+        }
+        _ => 256 << (block_size_bits - 8),
+    };
+
+    length += match sample_rate_bits {
+        0x0c => 1,
+        0x0d | 0x0e => 2,
+        _ => 0,
+    };
+
+    (crc8(data.get(..=length)?) == 0).then_some(block_size)
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |mut crc, &byte| {
+        crc ^= byte;
+
+        for _ in 0..8 {
+            crc = if crc & 0x80 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x07
+            };
+        }
+
+        crc
+    })
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        #[expect(clippy::cast_possible_truncation)]
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x8005
+            };
+
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    (crc << 8) ^ CRC16_TABLE[usize::from((crc >> 8) as u8 ^ byte)]
+}
+
+#[derive(Default)]
+enum Mp3Parser {
+    #[default]
+    Id3Tag,
+    /// two consecutive valid frame headers make a false positive unlikely
+    Frames { count: u8 },
+    /// the rest of the file isn't checked
+    Done,
+}
+
+impl Mp3Parser {
+    fn parse(
+        &mut self,
+        data: &[u8],
+        offset: u64,
+        is_end: bool,
+        format: &mut Option<AudioFormat>,
+    ) -> Result<Step, String> {
+        let count = match *self {
+            Self::Id3Tag => {
+                let Step::Parsed(length) = id3_tag_length(data) else {
+                    return Ok(Step::NeedMore);
+                };
+
+                *self = Self::Frames { count: 0 };
+                return Ok(Step::Parsed(length as u64));
+            }
+            Self::Frames { count } => count,
+            Self::Done => return Ok(Step::Parsed(data.len() as u64)),
+        };
+
+        let Some(header) = data.get(..4) else {
+            return Ok(Step::NeedMore);
+        };
+
+        let (frame_length, bitrate) = parse_mp3_frame_header(header)
+            .ok_or_else(|| format!("invalid MPEG frame header at offset {offset}"))?;
+
+        if count == 0 {
+            let Some(frame) = data.get(..frame_length) else {
+                // a truncated frame is skipped, so the file ends early
+                return Ok(if is_end {
+                    Step::Parsed(frame_length as u64)
+                } else {
+                    Step::NeedMore
+                });
+            };
+
+            // the first frame of a VBR file holds a Xing or VBRI header instead
+            // of audio
+            let is_vbr = contains(frame, b"Xing") || contains(frame, b"VBRI");
+
+            *format = Some(AudioFormat::Mp3 {
+                bitrate: (!is_vbr).then_some(bitrate),
+            });
+        }
+
+        *self = if count == 0 {
+            Self::Frames { count: 1 }
+        } else {
+            Self::Done
+        };
+
+        Ok(Step::Parsed(frame_length as u64))
+    }
+
+    fn finish(&self, offset: u64) -> Result<(), String> {
+        match self {
+            Self::Done => Ok(()),
+            _ => Err(format!("invalid MPEG frame header at offset {offset}")),
+        }
+    }
+}
+
+/// returns the length and the bitrate of the frame
+fn parse_mp3_frame_header(header: &[u8]) -> Option<(usize, u32)> {
+    const BITRATES: [[u32; 15]; 2] = [
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate_index = usize::from(header[2] >> 4);
+    let sample_rate_index = usize::from((header[2] >> 2) & 0x03);
+    let padding = u32::from((header[2] >> 1) & 0x01);
+
+    // only layer III is expected, as lucida serves MP3 files
+    if version == 0x01 || layer != 0x01 || bitrate_index == 0 || bitrate_index == 0x0f {
+        return None;
+    }
+
+    let is_mpeg1 = version == 0x03;
+    let bitrate = BITRATES[usize::from(!is_mpeg1)][bitrate_index] * 1000;
+
+    let sample_rate = SAMPLE_RATES.get(sample_rate_index)?
+        >> match version {
+            0x03 => 0,
+            0x02 => 1,
+            _ => 2,
+        };
+
+    let samples_per_slot = if is_mpeg1 { 144 } else { 72 };
+
+    Some((
+        (samples_per_slot * bitrate / sample_rate + padding) as usize,
+        bitrate,
+    ))
+}
+
+#[derive(Default)]
+struct Mp4Parser {
+    box_types: Vec<[u8; 4]>,
+    /// the last box extends to the end of the file
+    is_in_last_box: bool,
+}
+
+impl Mp4Parser {
+    fn parse(
+        &mut self,
+        data: &[u8],
+        offset: u64,
+        is_end: bool,
+        format: &mut Option<AudioFormat>,
+    ) -> Result<Step, String> {
+        if self.is_in_last_box {
+            return Ok(Step::Parsed(data.len() as u64));
+        }
+
+        let Some(header) = data.get(..8) else {
+            return Ok(Step::NeedMore);
+        };
+
+        let box_type = header[4..8].try_into().unwrap();
+
+        let (header_length, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (8, None),
+            1 => {
+                let Some(large_size) = data.get(8..16) else {
+                    return Ok(Step::NeedMore);
+                };
+
+                (16, Some(u64::from_be_bytes(large_size.try_into().unwrap())))
+            }
+            size => (8, Some(u64::from(size))),
+        };
+
+        if size.is_some_and(|size| size < header_length as u64) {
+            return Err(format!("invalid box size at offset {offset}"));
+        }
+
+        // the format is read from the moov box, which is kept whole
+        if box_type == *b"moov" {
+            let end = match size {
+                Some(size) if size > MAX_MP4_MOOV_SIZE => {
+                    return Err(format!("moov box at offset {offset} is too large"));
+                }
+                Some(size) => usize::try_from(size).unwrap(),
+                None if is_end => data.len(),
+                None => return Ok(Step::NeedMore),
+            };
+
+            let Some(moov) = data.get(header_length..end) else {
+                return Ok(Step::NeedMore);
+            };
+
+            *format = read_mp4_format(moov);
+        }
+
+        self.box_types.push(box_type);
+        self.is_in_last_box = size.is_none();
+
+        Ok(Step::Parsed(size.unwrap_or(data.len() as u64)))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if self.box_types.first() != Some(b"ftyp") {
+            return Err("file doesn't start with an ftyp box".into());
+        }
+
+        for required_box_type in [*b"moov", *b"mdat"] {
+            if !self.box_types.contains(&required_box_type) {
+                return Err(format!(
+                    "file is missing a {} box",
+                    String::from_utf8_lossy(&required_box_type)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// reads the format from the first sample entry of the tracks in the moov box,
+/// in trak/mdia/minf/stbl/stsd
+fn read_mp4_format(moov: &[u8]) -> Option<AudioFormat> {
+    mp4_boxes(moov)
+        .filter(|&(box_type, _)| box_type == *b"trak")
+        .find_map(|(_, trak)| {
+            let stbl = [*b"mdia", *b"minf", *b"stbl"]
+                .into_iter()
+                .try_fold(trak, |parent, box_type| find_mp4_box(parent, box_type))?;
+
+            // skip the version, flags and entry count
+            let (entry_type, entry) = mp4_boxes(find_mp4_box(stbl, *b"stsd")?.get(8..)?).next()?;
+
+            match &entry_type {
+                b"alac" => Some(AudioFormat::Alac),
+                b"mp4a" => Some(AudioFormat::Aac {
+                    bitrate: read_mp4a_average_bitrate(entry).filter(|&bitrate| bitrate != 0),
+                }),
+                _ => None,
+            }
+        })
+}
+
+/// iterates over the type and contents of the boxes, stopping at the first
+/// invalid one
+fn mp4_boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    iter::from_fn(move || {
+        let header = data.get(..8)?;
+        let box_type = header[4..8].try_into().unwrap();
+
+        let (header_length, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                usize::try_from(u64::from_be_bytes(data.get(8..16)?.try_into().unwrap())).ok()?,
+            ),
+            size => (8, size as usize),
+        };
+
+        let contents = data.get(header_length..size)?;
+        data = &data[size..];
+
+        Some((box_type, contents))
+    })
+}
+
+fn find_mp4_box(data: &[u8], box_type: [u8; 4]) -> Option<&[u8]> {
+    mp4_boxes(data).find_map(|(found_type, contents)| (found_type == box_type).then_some(contents))
+}
+
+/// reads the average bitrate from the decoder config descriptor in the esds
+/// box of the mp4a sample entry
+fn read_mp4a_average_bitrate(entry: &[u8]) -> Option<u32> {
+    // the fields of the audio sample entry are followed by more fields in
+    // version 1 and 2 of QuickTime sound sample descriptions
+    let fields_length = match u16::from_be_bytes(entry.get(8..10)?.try_into().unwrap()) {
+        1 => 28 + 16,
+        2 => 28 + 36,
+        _ => 28,
+    };
+
+    let esds = find_mp4_box(entry.get(fields_length..)?, *b"esds")?;
+
+    // skip the version and flags
+    let mut position = 4;
+
+    if *esds.get(position)? != 0x03 {
+        return None;
+    }
+
+    position = skip_mp4_descriptor_length(esds, position + 1)? + 2;
+    let flags = *esds.get(position)?;
+    position += 1;
+
+    if flags & 0x80 != 0 {
+        position += 2;
+    }
+
+    if flags & 0x40 != 0 {
+        position += 1 + usize::from(*esds.get(position)?);
+    }
+
+    if flags & 0x20 != 0 {
+        position += 2;
+    }
+
+    if *esds.get(position)? != 0x04 {
+        return None;
+    }
+
+    // skip the object type, stream type, buffer size and maximum bitrate
+    position = skip_mp4_descriptor_length(esds, position + 1)? + 9;
+
+    Some(u32::from_be_bytes(
+        esds.get(position..position + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn skip_mp4_descriptor_length(data: &[u8], mut position: usize) -> Option<usize> {
+    for _ in 0..4 {
+        let byte = *data.get(position)?;
+        position += 1;
+
+        if byte & 0x80 == 0 {
+            return Some(position);
+        }
+    }
+
+    None
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
+}
+
+#[derive(Default)]
+struct OggParser {
+    has_pages: bool,
+    is_last_page: bool,
+}
+
+impl OggParser {
+    fn parse(
+        &mut self,
+        data: &[u8],
+        offset: u64,
+        format: &mut Option<AudioFormat>,
+    ) -> Result<Step, String> {
+        let Some(header) = data.get(..27) else {
+            return Ok(Step::NeedMore);
+        };
+
+        if !header.starts_with(b"OggS") || header[4] != 0 {
+            return Err(format!("invalid page header at offset {offset}"));
+        }
+
+        let segment_count = usize::from(header[26]);
+
+        let Some(segment_table) = data.get(27..27 + segment_count) else {
+            return Ok(Step::NeedMore);
+        };
+
+        let page_length = 27
+            + segment_count
+            + segment_table
+                .iter()
+                .map(|&length| usize::from(length))
+                .sum::<usize>();
+
+        let Some(page) = data.get(..page_length) else {
+            return Ok(Step::NeedMore);
+        };
+
+        // the CRC is calculated with the CRC field set to zero
+        let crc = page.iter().enumerate().fold(0, |crc, (index, &byte)| {
+            crc32_update(crc, if (22..26).contains(&index) { 0 } else { byte })
+        });
+
+        if crc != u32::from_le_bytes(page[22..26].try_into().unwrap()) {
+            return Err(format!("page at offset {offset} failed the CRC check"));
+        }
+
+        if !self.has_pages {
+            *format = read_ogg_format(page);
+            self.has_pages = true;
+        }
+
+        self.is_last_page = header[5] & 0x04 != 0;
+        Ok(Step::Parsed(page_length as u64))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if !self.is_last_page {
+            return Err("last page doesn't end the stream".into());
+        }
+
+        Ok(())
+    }
+}
+
+fn read_ogg_format(data: &[u8]) -> Option<AudioFormat> {
+    // the identification header is the first packet of the first page
+    let packet = data.get(27 + usize::from(*data.get(26)?)..)?;
+
+    if packet.starts_with(b"OpusHead") {
+        Some(AudioFormat::Opus)
+    } else if packet.starts_with(b"\x01vorbis") {
+        let bitrate = i32::from_le_bytes(packet.get(20..24)?.try_into().unwrap());
+
+        Some(AudioFormat::Vorbis {
+            bitrate: u32::try_from(bitrate).ok().filter(|&bitrate| bitrate != 0),
+        })
+    } else {
+        None
+    }
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        #[expect(clippy::cast_possible_truncation)]
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x04c1_1db7
+            };
+
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    (crc << 8) ^ CRC32_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+}
+
+#[derive(Default)]
+enum AdtsParser {
+    #[default]
+    Id3Tag,
+    Frames {
+        has_frames: bool,
+    },
+}
+
+impl AdtsParser {
+    fn parse(
+        &mut self,
+        data: &[u8],
+        offset: u64,
+        format: &mut Option<AudioFormat>,
+    ) -> Result<Step, String> {
+        let Self::Frames { has_frames } = self else {
+            let Step::Parsed(length) = id3_tag_length(data) else {
+                return Ok(Step::NeedMore);
+            };
+
+            *self = Self::Frames { has_frames: false };
+            return Ok(Step::Parsed(length as u64));
+        };
+
+        let Some(header) = data.get(..7) else {
+            return Ok(Step::NeedMore);
+        };
+
+        let frame_length = (usize::from(header[3] & 0x03) << 11)
+            | (usize::from(header[4]) << 3)
+            | usize::from(header[5] >> 5);
+
+        if header[0] != 0xff || header[1] & 0xf6 != 0xf0 || frame_length < 7 {
+            return Err(format!("invalid ADTS frame header at offset {offset}"));
+        }
+
+        if !*has_frames {
+            *format = Some(AudioFormat::Aac { bitrate: None });
+            *has_frames = true;
+        }
+
+        Ok(Step::Parsed(frame_length as u64))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if !matches!(self, Self::Frames { has_frames: true }) {
+            return Err("file has no audio frames".into());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct WavParser {
+    /// length of the file declared by the RIFF header
+    riff_length: Option<u64>,
+    has_fmt: bool,
+    has_data: bool,
+    /// chunks are padded to an even length
+    is_padded: bool,
+}
+
+impl WavParser {
+    fn parse(&mut self, data: &[u8], format: &mut Option<AudioFormat>) -> Result<Step, String> {
+        if self.riff_length.is_none() {
+            let Some(header) = data.get(..12) else {
+                return Ok(Step::NeedMore);
+            };
+
+            if !header.starts_with(b"RIFF") || header[8..12] != *b"WAVE" {
+                return Err("missing RIFF WAVE header".into());
+            }
+
+            self.riff_length =
+                Some(u64::from(u32::from_le_bytes(header[4..8].try_into().unwrap())) + 8);
+
+            return Ok(Step::Parsed(12));
+        }
+
+        if self.is_padded {
+            self.is_padded = false;
+            return Ok(Step::Parsed(1));
+        }
+
+        let Some(header) = data.get(..8) else {
+            return Ok(Step::NeedMore);
+        };
+
+        let length = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        match &header[..4] {
+            b"fmt " => {
+                let Some(fmt) = data.get(8..8 + length as usize) else {
+                    return Ok(Step::NeedMore);
+                };
+
+                *format = read_wav_format(fmt);
+                self.has_fmt = true;
+            }
+            b"data" => self.has_data = true,
+            _ => {}
+        }
+
+        self.is_padded = length % 2 != 0;
+        Ok(Step::Parsed(8 + u64::from(length)))
+    }
+
+    fn finish(&self, length: u64) -> Result<(), String> {
+        if let Some(riff_length) = self.riff_length
+            && riff_length != length
+        {
+            return Err(format!(
+                "RIFF header declares {riff_length} bytes, file has {length}"
+            ));
+        }
+
+        for (chunk_id, is_present) in [("fmt", self.has_fmt), ("data", self.has_data)] {
+            if !is_present {
+                return Err(format!("file is missing a {chunk_id} chunk"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_wav_format(fmt: &[u8]) -> Option<AudioFormat> {
+    Some(AudioFormat::Wav {
+        bits_per_sample: *fmt.get(14)?,
+        sample_rate: u32::from_le_bytes(fmt.get(4..8)?.try_into().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAC_BLOCK_SIZE: u64 = 4096;
+    const MP3_FRAME_LENGTH: usize = 417;
+
+    /// feeds the file to a verifier in chunks of different sizes, which all
+    /// have to give the same result, and returns the extension and format
+    fn verify(
+        data: &[u8],
+        file_extension: Option<&'static str>,
+    ) -> Result<(String, String), String> {
+        let results = [1, 7, 4096, data.len().max(1)].map(|chunk_size| {
+            let mut verifier = AudioVerifier::new(file_extension);
+
+            for chunk in data.chunks(chunk_size) {
+                verifier.update(chunk)?;
+            }
+
+            verifier.finish().map(|(file_extension, format)| {
+                (
+                    file_extension.unwrap_or_default().to_owned(),
+                    format.map(|format| format.to_string()).unwrap_or_default(),
+                )
+            })
+        });
+
+        for result in &results[1..] {
+            assert_eq!(*result, results[0]);
+        }
+
+        results[0].clone()
+    }
+
+    fn flac_file(frame_count: u8, total_samples: u64) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+
+        // a STREAMINFO block for 16 bit stereo at 44.1 kHz, which is the last
+        // metadata block
+        data.extend([0x80, 0, 0, 34]);
+        data.extend([0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend(((44100 << 44) | (1 << 41) | (15 << 36) | total_samples).to_be_bytes());
+        data.extend([0; 16]);
+
+        for frame_number in 0..frame_count {
+            let mut frame = vec![0xff, 0xf8, 0xc9, 0x18, frame_number];
+            frame.push(crc8(&frame));
+            frame.extend((0..200_u8).map(|i| i.wrapping_mul(7) % 251));
+
+            let crc = frame.iter().fold(0, |crc, &byte| crc16_update(crc, byte));
+            frame.extend(crc.to_be_bytes());
+
+            data.extend(frame);
+        }
+
+        data
+    }
+
+    fn mp3_frame(is_vbr: bool) -> Vec<u8> {
+        // MPEG-1 layer III at 128 kbps and 44.1 kHz
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+        frame.resize(MP3_FRAME_LENGTH, 0);
+
+        if is_vbr {
+            frame[36..40].copy_from_slice(b"Xing");
+        }
+
+        frame
+    }
+
+    fn mp3_file(is_vbr: bool) -> Vec<u8> {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        data.extend([0; 20]);
+        data.extend(mp3_frame(is_vbr));
+        data.extend(mp3_frame(false));
+        data.extend(mp3_frame(false));
+        data
+    }
+
+    fn mp4_box(box_type: [u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut data = u32::try_from(8 + contents.len())
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+
+        data.extend(box_type);
+        data.extend(contents);
+        data
+    }
+
+    fn mp4_file(boxes: &[[u8; 4]]) -> Vec<u8> {
+        boxes
+            .iter()
+            .flat_map(|&box_type| match &box_type {
+                b"ftyp" => mp4_box(box_type, b"M4A \0\0\0\0"),
+                _ => mp4_box(box_type, &[0; 64]),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(crc8(b"123456789"), 0xf4);
+
+        assert_eq!(
+            b"123456789"
+                .iter()
+                .fold(0, |crc, &byte| crc16_update(crc, byte)),
+            0xfee8
+        );
+
+        assert_eq!(
+            b"123456789"
+                .iter()
+                .fold(0, |crc, &byte| crc32_update(crc, byte)),
+            0x89a1_897f
+        );
+    }
+
+    #[test]
+    fn flac_frame_header() {
+        let mut header = vec![0xff, 0xf8, 0xc9, 0x18, 0x00];
+        header.push(crc8(&header));
+        assert_eq!(parse_flac_frame_header(&header), Some(4096));
+
+        // block size stored in the 8 bits after the frame number
+        let mut header = vec![0xff, 0xf8, 0x69, 0x18, 0x00, 0xff];
+        header.push(crc8(&header));
+        assert_eq!(parse_flac_frame_header(&header), Some(256));
+
+        let mut corrupt_header = header.clone();
+        corrupt_header[4] = 0x01;
+        assert_eq!(parse_flac_frame_header(&corrupt_header), None);
+
+        // reserved block size and invalid frame number
+        assert_eq!(
+            parse_flac_frame_header(&[0xff, 0xf8, 0x09, 0x18, 0x00, 0x00]),
+            None
+        );
+        assert_eq!(
+            parse_flac_frame_header(&[0xff, 0xf8, 0xc9, 0x18, 0xff, 0x00]),
+            None
+        );
+        assert_eq!(parse_flac_frame_header(&header[..4]), None);
+    }
+
+    #[test]
+    fn flac_valid() {
+        assert_eq!(
+            verify(&flac_file(3, 3 * FLAC_BLOCK_SIZE), Some("flac")),
+            Ok(("flac".into(), "FLAC 16-44.1".into()))
+        );
+
+        // the total is unknown
+        assert!(verify(&flac_file(3, 0), Some("flac")).is_ok());
+    }
+
+    #[test]
+    fn flac_corrupt() {
+        let file = flac_file(3, 3 * FLAC_BLOCK_SIZE);
+
+        let mut corrupt_file = file.clone();
+        corrupt_file[200] ^= 0x01;
+        assert!(
+            verify(&corrupt_file, Some("flac"))
+                .unwrap_err()
+                .contains("CRC check")
+        );
+
+        assert!(verify(&file[..file.len() - 10], Some("flac")).is_err());
+
+        assert!(
+            verify(&flac_file(2, 3 * FLAC_BLOCK_SIZE), Some("flac"))
+                .unwrap_err()
+                .contains("STREAMINFO declares")
+        );
+
+        assert!(
+            verify(&flac_file(0, 0), Some("flac"))
+                .unwrap_err()
+                .contains("no audio frames")
+        );
+
+        assert!(
+            verify(&file[4..], Some("flac"))
+                .unwrap_err()
+                .contains("fLaC")
+        );
+    }
+
+    #[test]
+    fn mp3_frame_header() {
+        assert_eq!(
+            parse_mp3_frame_header(&[0xff, 0xfb, 0x90, 0x00]),
+            Some((417, 128_000))
+        );
+
+        // padded MPEG-2 frame
+        assert_eq!(
+            parse_mp3_frame_header(&[0xff, 0xf3, 0x92, 0x00]),
+            Some((262, 80_000))
+        );
+
+        // layer II, free and invalid bitrates and a reserved sample rate
+        assert_eq!(parse_mp3_frame_header(&[0xff, 0xfd, 0x90, 0x00]), None);
+        assert_eq!(parse_mp3_frame_header(&[0xff, 0xfb, 0x00, 0x00]), None);
+        assert_eq!(parse_mp3_frame_header(&[0xff, 0xfb, 0xf0, 0x00]), None);
+        assert_eq!(parse_mp3_frame_header(&[0xff, 0xfb, 0x9c, 0x00]), None);
+        assert_eq!(parse_mp3_frame_header(&[0x00, 0xfb, 0x90, 0x00]), None);
+    }
+
+    #[test]
+    fn mp3_valid() {
+        assert_eq!(
+            verify(&mp3_file(false), Some("mp3")),
+            Ok(("mp3".into(), "MP3 128".into()))
+        );
+
+        assert_eq!(
+            verify(&mp3_file(true), Some("mp3")),
+            Ok(("mp3".into(), "MP3 VBR".into()))
+        );
+    }
+
+    #[test]
+    fn mp3_corrupt() {
+        let file = mp3_file(false);
+
+        assert!(verify(&file[..30 + MP3_FRAME_LENGTH], Some("mp3")).is_err());
+
+        assert!(
+            verify(&file[..30 + MP3_FRAME_LENGTH + 100], Some("mp3"))
+                .unwrap_err()
+                .contains("ends")
+        );
+
+        let mut corrupt_file = file;
+        corrupt_file[30 + MP3_FRAME_LENGTH] = 0;
+        assert!(
+            verify(&corrupt_file, Some("mp3"))
+                .unwrap_err()
+                .contains("frame header")
+        );
+
+        assert!(verify(&[0; 100], Some("mp3")).is_err());
+    }
+
+    #[test]
+    fn mp4_boxes_valid() {
+        assert!(verify(&mp4_file(&[*b"ftyp", *b"moov", *b"mdat"]), Some("m4a")).is_ok());
+        assert!(
+            verify(
+                &mp4_file(&[*b"ftyp", *b"mdat", *b"free", *b"moov"]),
+                Some("m4a")
+            )
+            .is_ok()
+        );
+
+        // the last box extends to the end of the file
+        let mut file = mp4_file(&[*b"ftyp", *b"moov"]);
+        file.extend(0_u32.to_be_bytes());
+        file.extend(b"mdat");
+        file.extend([0; 100]);
+        assert!(verify(&file, Some("m4a")).is_ok());
+    }
+
+    #[test]
+    fn mp4_boxes_corrupt() {
+        assert!(
+            verify(&mp4_file(&[*b"ftyp", *b"mdat"]), Some("m4a"))
+                .unwrap_err()
+                .contains("moov")
+        );
+
+        assert!(
+            verify(&mp4_file(&[*b"moov", *b"mdat"]), Some("m4a"))
+                .unwrap_err()
+                .contains("ftyp")
+        );
+
+        let file = mp4_file(&[*b"ftyp", *b"moov", *b"mdat"]);
+        assert!(verify(&file[..file.len() - 1], Some("m4a")).is_err());
+
+        let mut file = mp4_file(&[*b"ftyp", *b"moov"]);
+        file.extend([0, 0, 0, 4]);
+        file.extend(b"mdat");
+        assert!(verify(&file, Some("m4a")).unwrap_err().contains("box size"));
+
+        // a large size that would overflow the offset
+        let mut file = mp4_file(&[*b"ftyp", *b"moov"]);
+        file.extend(1_u32.to_be_bytes());
+        file.extend(b"mdat");
+        file.extend(u64::MAX.to_be_bytes());
+        assert!(verify(&file, Some("m4a")).unwrap_err().contains("ends"));
+
+        let mut file = mp4_file(&[*b"ftyp"]);
+        file.extend(u32::MAX.to_be_bytes());
+        file.extend(b"moov");
+        assert!(
+            verify(&file, Some("m4a"))
+                .unwrap_err()
+                .contains("too large")
+        );
+    }
+
+    #[test]
+    fn skipped_bytes() {
+        let file = mp3_file(false);
+        let mut verifier = AudioVerifier::new(Some("mp3"));
+
+        verifier.update(&file[..40]).unwrap();
+        assert!(verifier.format().is_none());
+
+        // the second frame is skipped over after its header
+        verifier
+            .update(&file[40..30 + MP3_FRAME_LENGTH + 4])
+            .unwrap();
+
+        assert_eq!(verifier.format().unwrap().to_string(), "MP3 128");
+        assert_eq!(verifier.take_skip(), MP3_FRAME_LENGTH as u64 - 4);
+
+        verifier.update(&file[30 + 2 * MP3_FRAME_LENGTH..]).unwrap();
+        assert!(verifier.finish().is_ok());
+    }
+
+    fn mp4a_entry(version: u16, average_bitrate: u32) -> Vec<u8> {
+        let mut entry = vec![0; 28];
+        entry[8..10].copy_from_slice(&version.to_be_bytes());
+        // the QuickTime fields of version 1 and 2 sound sample descriptions
+        entry.resize(
+            match version {
+                1 => 28 + 16,
+                2 => 28 + 36,
+                _ => 28,
+            },
+            0,
+        );
+
+        // ES descriptor holding a decoder config descriptor
+        let mut esds = vec![
+            0, 0, 0, 0, 0x03, 0x19, 0, 1, 0, 0x04, 0x11, 0x40, 0x15, 0, 0, 0,
+        ];
+        esds.extend(320_000_u32.to_be_bytes());
+        esds.extend(average_bitrate.to_be_bytes());
+
+        entry.extend(mp4_box(*b"esds", &esds));
+        mp4_box(*b"mp4a", &entry)
+    }
+
+    fn mp4_moov(entry: &[u8]) -> Vec<u8> {
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend(entry);
+
+        let trak = [*b"stbl", *b"minf", *b"mdia", *b"trak"]
+            .into_iter()
+            .fold(mp4_box(*b"stsd", &stsd), |contents, box_type| {
+                mp4_box(box_type, &contents)
+            });
+
+        [mp4_box(*b"mvhd", &[0; 100]), trak].concat()
+    }
+
+    fn mp4_format(entry: &[u8]) -> Option<String> {
+        read_mp4_format(&mp4_moov(entry)).map(|format| format.to_string())
+    }
+
+    #[test]
+    fn mp4_format_from_sample_entry() {
+        assert_eq!(mp4_format(&mp4a_entry(0, 256_000)), Some("AAC 256".into()));
+        assert_eq!(mp4_format(&mp4a_entry(1, 255_500)), Some("AAC 256".into()));
+        assert_eq!(mp4_format(&mp4a_entry(2, 128_000)), Some("AAC 128".into()));
+        assert_eq!(mp4_format(&mp4a_entry(0, 0)), Some("AAC".into()));
+        assert_eq!(
+            mp4_format(&mp4_box(*b"alac", &[0; 64])),
+            Some("ALAC".into())
+        );
+        assert_eq!(mp4_format(&mp4_box(*b"avc1", &[0; 64])), None);
+
+        let mut file = mp4_file(&[*b"ftyp"]);
+        file.extend(mp4_box(*b"moov", &mp4_moov(&mp4a_entry(0, 256_000))));
+        file.extend(mp4_box(*b"mdat", &[0; 100]));
+
+        assert_eq!(
+            verify(&file, Some("m4a")),
+            Ok(("m4a".into(), "AAC 256".into()))
+        );
+    }
+
+    #[test]
+    fn mp4_format_from_corrupt_sample_entry() {
+        let entry = mp4a_entry(0, 256_000);
+
+        // a truncated esds box leaves the bitrate unknown
+        let mut truncated_entry = entry[..entry.len() - 2].to_vec();
+        truncated_entry[..4]
+            .copy_from_slice(&(u32::try_from(entry.len()).unwrap() - 2).to_be_bytes());
+        assert_eq!(mp4_format(&truncated_entry), Some("AAC".into()));
+
+        let mut corrupt_entry = entry.clone();
+        corrupt_entry[8 + 28 + 8 + 4] = 0x05;
+        assert_eq!(mp4_format(&corrupt_entry), Some("AAC".into()));
+
+        // a sample entry larger than its parent boxes
+        let mut oversized_entry = entry;
+        oversized_entry[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(mp4_format(&oversized_entry), None);
+
+        assert!(read_mp4_format(&[]).is_none());
+        assert!(read_mp4_format(&mp4_box(*b"trak", &[0; 16])).is_none());
+    }
+
+    #[test]
+    fn better_formats() {
+        let flac = |bits_per_sample, sample_rate| AudioFormat::Flac {
+            bits_per_sample,
+            sample_rate,
+        };
+
+        let mp3 = |bitrate| AudioFormat::Mp3 { bitrate };
+        let aac = |bitrate| AudioFormat::Aac { bitrate };
+
+        let better_formats = [
+            (flac(16, 44100), mp3(Some(320_000))),
+            (AudioFormat::Alac, aac(Some(256_000))),
+            (flac(24, 96000), flac(16, 44100)),
+            (flac(24, 44100), flac(16, 44100)),
+            (aac(Some(256_000)), mp3(Some(320_000))),
+            (mp3(Some(320_000)), mp3(Some(256_000))),
+        ];
+
+        for (format, other_format) in better_formats {
+            assert!(
+                format.is_better_than(&other_format),
+                "{format} {other_format}"
+            );
+            assert!(
+                !other_format.is_better_than(&format),
+                "{other_format} {format}"
+            );
+        }
+
+        // neither is better
+        let incomparable_formats = [
+            (flac(24, 44100), flac(16, 96000)),
+            (flac(16, 44100), flac(16, 44100)),
+            (flac(16, 44100), AudioFormat::Alac),
+            (mp3(None), mp3(Some(320_000))),
+            (aac(None), AudioFormat::Opus),
+        ];
+
+        for (format, other_format) in incomparable_formats {
+            assert!(
+                !format.is_better_than(&other_format),
+                "{format} {other_format}"
+            );
+            assert!(
+                !other_format.is_better_than(&format),
+                "{other_format} {format}"
+            );
+        }
+    }
+
+    #[test]
+    fn format_names() {
+        let formats = [
+            (
+                AudioFormat::Flac {
+                    bits_per_sample: 24,
+                    sample_rate: 96000,
+                },
+                "FLAC 24-96",
+            ),
+            (
+                AudioFormat::Flac {
+                    bits_per_sample: 16,
+                    sample_rate: 44100,
+                },
+                "FLAC 16-44.1",
+            ),
+            (
+                AudioFormat::Mp3 {
+                    bitrate: Some(320_000),
+                },
+                "MP3 320",
+            ),
+            (
+                AudioFormat::Aac {
+                    bitrate: Some(255_900),
+                },
+                "AAC 256",
+            ),
+            (AudioFormat::Alac, "ALAC"),
+        ];
+
+        for (format, name) in formats {
+            assert_eq!(format.to_string(), name);
+        }
+    }
+
+    fn ogg_page(header_type: u8, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        page.extend([0; 20]);
+        page.push(1);
+        page.push(u8::try_from(packet.len()).unwrap());
+        page.extend(packet);
+
+        let crc = page.iter().fold(0, |crc, &byte| crc32_update(crc, byte));
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    fn ogg_file(identification_header: &[u8]) -> Vec<u8> {
+        [
+            ogg_page(0x02, identification_header),
+            ogg_page(0x00, b"comments"),
+            ogg_page(0x04, &[0x55; 200]),
+        ]
+        .concat()
+    }
+
+    fn vorbis_identification_header(bitrate: i32) -> Vec<u8> {
+        let mut header = b"\x01vorbis\0\0\0\0\x02".to_vec();
+        header.extend(44100_u32.to_le_bytes());
+        header.extend(0_i32.to_le_bytes());
+        header.extend(bitrate.to_le_bytes());
+        header.extend([0; 6]);
+        header
+    }
+
+    #[expect(clippy::cast_possible_truncation)]
+    fn adts_frame(length: usize) -> Vec<u8> {
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            0x50,
+            0x80 | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length as u8 & 0x07) << 5) | 0x1f,
+            0xfc,
+        ];
+
+        frame.resize(length, 0x21);
+        frame
+    }
+
+    fn wav_file(riff_length_change: i32, chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+
+        for (chunk_id, contents) in chunks {
+            data.extend(*chunk_id);
+            data.extend(u32::try_from(contents.len()).unwrap().to_le_bytes());
+            data.extend(*contents);
+
+            if contents.len() % 2 != 0 {
+                data.push(0);
+            }
+        }
+
+        let riff_length = u32::try_from(data.len() - 8)
+            .unwrap()
+            .wrapping_add_signed(riff_length_change);
+
+        data[4..8].copy_from_slice(&riff_length.to_le_bytes());
+        data
+    }
+
+    /// a fmt chunk for 24 bit stereo PCM at 48 kHz
+    fn wav_fmt() -> Vec<u8> {
+        let mut fmt = vec![0x01, 0, 0x02, 0];
+        fmt.extend(48000_u32.to_le_bytes());
+        fmt.extend(288_000_u32.to_le_bytes());
+        fmt.extend([6, 0, 24, 0]);
+        fmt
+    }
+
+    #[test]
+    fn mime_type_extensions() {
+        let mime_types = [
+            ("audio/flac", Some("flac")),
+            ("Audio/MPEG", Some("mp3")),
+            ("audio/x-m4a", Some("m4a")),
+            ("audio/ogg; codecs=opus", Some("opus")),
+            ("audio/ogg", Some("ogg")),
+            ("audio/aacp", Some("aac")),
+            ("audio/wav ; charset=binary", Some("wav")),
+            ("application/octet-stream", None),
+            ("", None),
+        ];
+
+        for (mime_type, file_extension) in mime_types {
+            assert_eq!(
+                mime_type_extension(mime_type),
+                file_extension,
+                "{mime_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn sniffed_extensions() {
+        let mut mp4 = mp4_file(&[*b"ftyp", *b"mdat"]);
+        mp4.extend(mp4_box(*b"moov", &mp4_moov(&mp4_box(*b"alac", &[0; 64]))));
+
+        let mut id3_adts = b"ID3\x04\x00\x10\x00\x00\x00\x04".to_vec();
+        id3_adts.extend([0; 14]);
+        id3_adts.extend(adts_frame(100));
+
+        let files = [
+            (flac_file(1, FLAC_BLOCK_SIZE), "flac"),
+            (mp3_file(false), "mp3"),
+            (mp3_file(false)[30..].to_vec(), "mp3"),
+            (mp4, "m4a"),
+            (ogg_file(b"OpusHead\x01\x02\0\0\x80\xbb\0\0\0\0\0"), "opus"),
+            (ogg_file(&vorbis_identification_header(0)), "ogg"),
+            (id3_adts, "aac"),
+            (
+                wav_file(0, &[(b"fmt ", &wav_fmt()), (b"data", &[0; 40])]),
+                "wav",
+            ),
+        ];
+
+        for (file, file_extension) in files {
+            assert_eq!(
+                verify(&file, None).map(|(sniffed_extension, _)| sniffed_extension),
+                Ok(file_extension.into())
+            );
+        }
+
+        // unknown files aren't checked, however short they are
+        assert_eq!(
+            verify(&[0x42; 1000], None),
+            Ok((String::new(), String::new()))
+        );
+        assert_eq!(verify(b"ID", None), Ok((String::new(), String::new())));
+        assert_eq!(
+            verify(b"ID3\0\0\0\0\0\0\x7f", None),
+            Ok((String::new(), String::new()))
+        );
+    }
+
+    #[test]
+    fn ogg_valid() {
+        assert_eq!(
+            verify(
+                &ogg_file(&vorbis_identification_header(160_000)),
+                Some("ogg")
+            ),
+            Ok(("ogg".into(), "VORBIS 160".into()))
+        );
+
+        assert_eq!(
+            verify(&ogg_file(&vorbis_identification_header(-1)), Some("ogg")),
+            Ok(("ogg".into(), "VORBIS".into()))
+        );
+
+        assert_eq!(
+            verify(&ogg_file(b"OpusHead\x01\x02\0\0"), Some("opus")),
+            Ok(("opus".into(), "OPUS".into()))
+        );
+    }
+
+    #[test]
+    fn ogg_corrupt() {
+        let file = ogg_file(&vorbis_identification_header(160_000));
+
+        let mut corrupt_file = file.clone();
+        corrupt_file[100] ^= 0x01;
+        assert!(
+            verify(&corrupt_file, Some("ogg"))
+                .unwrap_err()
+                .contains("CRC check")
+        );
+
+        assert!(verify(&file[..file.len() - 1], Some("ogg")).is_err());
+
+        let file_without_end = &file[..file.len() - 27 - 1 - 200];
+        assert!(
+            verify(file_without_end, Some("ogg"))
+                .unwrap_err()
+                .contains("doesn't end")
+        );
+
+        assert!(
+            verify(&file[1..], Some("ogg"))
+                .unwrap_err()
+                .contains("page header")
+        );
+    }
+
+    #[test]
+    fn adts_valid() {
+        let file = [adts_frame(100), adts_frame(371), adts_frame(2500)].concat();
+
+        assert_eq!(verify(&file, Some("aac")), Ok(("aac".into(), "AAC".into())));
+    }
+
+    #[test]
+    fn adts_corrupt() {
+        let file = [adts_frame(100), adts_frame(371)].concat();
+
+        assert!(
+            verify(&file[..file.len() - 1], Some("aac"))
+                .unwrap_err()
+                .contains("ends")
+        );
+        assert!(
+            verify(&file[1..], Some("aac"))
+                .unwrap_err()
+                .contains("frame header")
+        );
+        assert!(verify(&adts_frame(6)[..6], Some("aac")).is_err());
+        assert!(
+            verify(&[], Some("aac"))
+                .unwrap_err()
+                .contains("no audio frames")
+        );
+    }
+
+    #[test]
+    fn wav_valid() {
+        let file = wav_file(
+            0,
+            &[
+                (b"fmt ", &wav_fmt()),
+                (b"LIST", &[0; 7]),
+                (b"data", &[0; 101]),
+            ],
+        );
+
+        assert_eq!(
+            verify(&file, Some("wav")),
+            Ok(("wav".into(), "WAV 24-48".into()))
+        );
+    }
+
+    #[test]
+    fn wav_corrupt() {
+        let fmt = wav_fmt();
+        let file = wav_file(0, &[(b"fmt ", &fmt), (b"data", &[0; 100])]);
+
+        assert!(
+            verify(&file[..file.len() - 1], Some("wav"))
+                .unwrap_err()
+                .contains("ends")
+        );
+
+        assert!(
+            verify(
+                &wav_file(4, &[(b"fmt ", &fmt), (b"data", &[0; 100])]),
+                Some("wav")
+            )
+            .unwrap_err()
+            .contains("RIFF header declares")
+        );
+
+        assert!(
+            verify(&wav_file(0, &[(b"fmt ", &fmt)]), Some("wav"))
+                .unwrap_err()
+                .contains("data chunk")
+        );
+
+        assert!(
+            verify(&wav_file(0, &[(b"data", &[0; 100])]), Some("wav"))
+                .unwrap_err()
+                .contains("fmt chunk")
+        );
+
+        assert!(
+            verify(&file[4..], Some("wav"))
+                .unwrap_err()
+                .contains("RIFF")
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use time::{OffsetDateTime, UtcOffset};
+use tokio::sync::Mutex;
+
+use crate::models::{ByteRate, RateWindow};
+
+/// shared by the streams of all tracks and covers
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+struct RateLimiter {
+    rate: Option<ByteRate>,
+    schedule: Vec<RateWindow>,
+    /// offset of the local time the schedule is in
+    offset: UtcOffset,
+    bucket: Mutex<Bucket>,
+}
+
+/// bytes that can be downloaded right away. downloads go into debt and wait
+/// for it to be paid off, so the rate holds for chunks of any size
+struct Bucket {
+    available: f64,
+    updated: Instant,
+    rate: Option<ByteRate>,
+}
+
+/// limits the download speed of all downloads. the schedule takes precedence
+/// over the rate during its windows
+pub fn limit_rate(rate: Option<ByteRate>, schedule: Vec<RateWindow>) {
+    if rate.is_none() && schedule.is_empty() {
+        return;
+    }
+
+    // the local offset can only be determined reliably while the program is
+    // single-threaded, so daylight saving time changes during a run are missed
+    let offset = UtcOffset::current_local_offset().unwrap_or_else(|_| {
+        tracing::warn!("cannot determine the local time zone, using UTC for the schedule");
+        UtcOffset::UTC
+    });
+
+    RATE_LIMITER.get_or_init(|| RateLimiter {
+        rate,
+        schedule,
+        offset,
+        bucket: Mutex::new(Bucket {
+            available: 0.0,
+            updated: Instant::now(),
+            rate: None,
+        }),
+    });
+}
+
+/// waits until `length` more bytes can be downloaded without exceeding the
+/// rate limit
+pub async fn throttle(length: usize) {
+    let Some(limiter) = RATE_LIMITER.get() else {
+        return;
+    };
+
+    let delay = limiter
+        .bucket
+        .lock()
+        .await
+        .take(limiter.current_rate(), length);
+
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+impl RateLimiter {
+    fn current_rate(&self) -> Option<ByteRate> {
+        let now = OffsetDateTime::now_utc().to_offset(self.offset);
+        let minute = u16::from(now.hour()) * 60 + u16::from(now.minute());
+
+        self.schedule
+            .iter()
+            .find(|window| window.contains(minute))
+            .map_or(self.rate, |window| window.rate)
+    }
+}
+
+impl Bucket {
+    /// takes `length` bytes from the bucket and returns how long to wait until
+    /// the debt is paid off
+    fn take(&mut self, rate: Option<ByteRate>, length: usize) -> Option<Duration> {
+        if rate != self.rate {
+            if let Some(rate) = rate {
+                tracing::info!("limiting download speed to {rate}");
+            } else {
+                tracing::info!("not limiting download speed");
+            }
+
+            self.rate = rate;
+        }
+
+        let rate = f64::from(rate?.0);
+        let now = Instant::now();
+
+        // at most a second worth of bytes is saved up, so the speed doesn't
+        // spike after idling or when the schedule switches to a lower rate
+        self.available = now
+            .duration_since(self.updated)
+            .as_secs_f64()
+            .mul_add(rate, self.available)
+            .min(rate);
+
+        self.updated = now;
+        self.available -= f64::from(u32::try_from(length).unwrap());
+
+        // later downloads add to the debt, so they wait until the earlier
+        // ones are paid off too
+        (self.available < 0.0).then(|| Duration::from_secs_f64(-self.available / rate))
+    }
+}
@@ -1,22 +1,34 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::process::ExitCode;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{env, process};
 
 use clap::Parser;
 use futures::future;
-use models::{BASE_URL, Cli, DownloadConfig, SkipConfig};
-use reqwest::ClientBuilder;
+use models::{BASE_URL, Cli, Command, ConnectionArgs, DirectoryArgs, ExistingFiles, SkipConfig};
 use reqwest::header::{COOKIE, HeaderMap};
+use reqwest::{Client, ClientBuilder};
 use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::signal;
+use tokio::sync;
+use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
 
 use crate::models::Availability;
 
+mod checks;
+mod cleanups;
+mod controls;
 mod downloaders;
+mod filters;
+mod integrity;
+mod limiters;
+mod manifests;
 mod models;
+mod playlists;
+mod queues;
 mod requests;
 mod text_utils;
 mod workers;
@@ -45,17 +57,16 @@ async fn main() -> ExitCode {
 
     let cli = Cli::parse();
 
-    let mut urls = cli.urls;
-
-    for file in cli.file {
-        let mut lines = BufReader::new(File::open(file).await.unwrap()).lines();
-
-        while let Some(line) = lines.next_line().await.unwrap() {
-            urls.push(line);
-        }
+    if let Some(command) = cli.command {
+        return run_command(command).await;
     }
 
-    urls.reverse();
+    // before anything spawns threads, see `limiters::limit_rate`
+    limiters::limit_rate(cli.limit_rate, cli.limit_schedule);
+
+    let output = output_path(&cli.directories);
+    let files = queues::add_queue_file(cli.file, &output, cli.resume).await;
+    let urls = read_urls(cli.urls, files).await;
 
     if urls.is_empty() {
         tracing::error!("no URLs to download");
@@ -64,93 +75,244 @@ async fn main() -> ExitCode {
 
     let urls_len = urls.len();
 
-    let client = {
-        let mut client = ClientBuilder::new();
-
-        if let Some(user_agent) = &cli.user_agent {
-            client = client.user_agent(user_agent);
-        }
-
-        if let Some(cf_clearance) = &cli.cf_clearance {
-            client = client.default_headers(HeaderMap::from_iter([(
-                COOKIE,
-                format!("cf_clearance={cf_clearance}").try_into().unwrap(),
-            )]));
-        }
-
-        client.build().unwrap()
+    let Some(client) = connect(&cli.connection).await else {
+        return ExitCode::FAILURE;
     };
 
-    match requests::check_availability(&client).await {
-        Availability::Available => (),
-        Availability::Captcha => {
-            if cli.cf_clearance.is_some() && cli.user_agent.is_some() {
-                tracing::error!(
-                    "Your cf_clearance cookie and User-Agent header weren't accepted. They might be stale"
-                );
-            } else {
-                tracing::error!("{CAPTCHA_PROMPT}");
-            }
-
-            return ExitCode::FAILURE;
-        }
-        Availability::Unavailable => {
-            tracing::error!(
-                "lucida seems to be unavailable right now. Visit the website: {BASE_URL}"
-            );
-            return ExitCode::FAILURE;
-        }
-    }
-
+    cleanups::remove_orphaned_part_files(&output).await;
     tracing::info!("downloading {urls_len} albums");
 
+    let queued_urls = urls.clone();
     let urls = Arc::new(Mutex::new(urls));
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = running.clone();
+    let cancel = CancellationToken::new();
     let worker_count = cli.album_workers.min(urls_len);
 
     tracing::info!("spawning {worker_count} album workers");
 
-    tokio::spawn(async move {
-        signal::ctrl_c().await.unwrap();
-        running_clone.store(false, Ordering::Relaxed);
-        tracing::warn!("Stopping gracefully");
-        signal::ctrl_c().await.unwrap();
-        process::exit(1);
-    });
+    handle_signals(cancel.clone());
 
-    let output = cli.output.unwrap_or_else(|| env::current_dir().unwrap());
+    let existing_files = ExistingFiles::new(cli.force, cli.upgrade);
+    let playlist_formats = Arc::<[_]>::from(cli.download.playlist_format.clone());
+
+    let filter = Arc::new(cli.filter.filter());
+
+    let config = cli.download.config(&cli.connection);
+
+    let (resolvers, albums_rx) = workers::spawn_album_resolvers(
+        &client,
+        cli.prefetch_albums.max(1),
+        &urls,
+        &config,
+        cli.expand_tracks,
+        &cancel,
+    );
+
+    let albums_rx = Arc::new(sync::Mutex::new(albums_rx));
+
+    let mut finished_urls = HashSet::new();
+    let mut skipped_urls = HashSet::new();
 
     for result in future::join_all((1..=worker_count).map(|album_worker| {
         tokio::spawn(
             workers::run_album_worker(
                 client.clone(),
-                urls.clone(),
+                albums_rx.clone(),
                 output.clone(),
-                cli.force,
-                cli.group_singles,
-                cli.album_year,
-                cli.flatten_directories,
-                DownloadConfig {
-                    country: cli.country.clone(),
-                    metadata: !cli.no_metadata,
-                    private: cli.private,
-                },
-                cli.track_workers,
+                existing_files,
+                cli.directories
+                    .config(cli.playlist_library, cli.remove_empty_albums),
+                config.clone(),
+                cli.download.workers(),
+                filter.clone(),
                 SkipConfig {
                     tracks: cli.skip_tracks,
                     cover: cli.skip_cover,
+                    playlist: cli.skip_playlist,
                 },
-                running.clone(),
+                playlist_formats.clone(),
+                cli.sync.then_some(cli.sync_removed),
+                cli.album_timeout,
+                cancel.clone(),
             )
             .instrument(tracing::info_span!("album", album_worker)),
         )
     }))
     .await
     {
-        result.unwrap();
+        let (worker_finished_urls, timed_out_urls) = result.unwrap();
+        finished_urls.extend(worker_finished_urls);
+        skipped_urls.extend(timed_out_urls);
     }
 
+    // the resolvers stop once the album workers dropped the receiver
+    drop(albums_rx);
+
+    let mut unresolved_urls = Vec::new();
+
+    for result in future::join_all(resolvers).await {
+        let (resolver_unresolved_urls, invalid_urls) = result.unwrap();
+        unresolved_urls.extend(resolver_unresolved_urls);
+        // URLs that can never be downloaded aren't saved to the queue
+        finished_urls.extend(invalid_urls);
+    }
+
+    report_summary(&unresolved_urls, config.accounts.len() > 1);
+    // unresolved URLs are saved to be tried again with --resume
+    skipped_urls.extend(unresolved_urls);
+
+    cleanups::remove_part_files().await;
+
+    let is_stopped = cancel.is_cancelled();
+    queues::update_queue(
+        &output,
+        queued_urls,
+        &finished_urls,
+        &skipped_urls,
+        is_stopped,
+        cli.resume,
+    )
+    .await;
+
     tracing::info!("finished!");
     ExitCode::SUCCESS
 }
+
+/// stops gracefully on the first Ctrl+C and exits on the second one, and
+/// listens for pausing and resuming
+fn handle_signals(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        signal::ctrl_c().await.unwrap();
+        cancel.cancel();
+        tracing::warn!("Stopping gracefully");
+        // the current items can't finish while paused
+        controls::resume();
+        signal::ctrl_c().await.unwrap();
+        process::exit(1);
+    });
+
+    controls::listen();
+}
+
+/// lists what went wrong during the run
+fn report_summary(unresolved_urls: &[String], has_multiple_accounts: bool) {
+    if !unresolved_urls.is_empty() {
+        tracing::error!(
+            "could not resolve {} URLs: {}",
+            unresolved_urls.len(),
+            unresolved_urls.join(", ")
+        );
+    }
+
+    if has_multiple_accounts {
+        requests::report_accounts();
+    }
+
+    downloaders::report_unavailable_tracks();
+}
+
+/// builds the client and makes sure lucida can be used with it
+async fn connect(connection: &ConnectionArgs) -> Option<Client> {
+    let client = build_client(
+        connection.user_agent.as_deref(),
+        connection.cf_clearance.as_deref(),
+    );
+
+    match requests::check_availability(&client).await {
+        Availability::Available => Some(client),
+        Availability::Captcha => {
+            if connection.cf_clearance.is_some() && connection.user_agent.is_some() {
+                tracing::error!(
+                    "Your cf_clearance cookie and User-Agent header weren't accepted. They might be stale"
+                );
+            } else {
+                tracing::error!("{CAPTCHA_PROMPT}");
+            }
+
+            None
+        }
+        Availability::Unavailable => {
+            tracing::error!(
+                "lucida seems to be unavailable right now. Visit the website: {BASE_URL}"
+            );
+            None
+        }
+    }
+}
+
+fn output_path(directories: &DirectoryArgs) -> PathBuf {
+    directories
+        .output
+        .clone()
+        .unwrap_or_else(|| env::current_dir().unwrap())
+}
+
+fn build_client(user_agent: Option<&str>, cf_clearance: Option<&str>) -> Client {
+    let mut client = ClientBuilder::new();
+
+    if let Some(user_agent) = user_agent {
+        client = client.user_agent(user_agent);
+    }
+
+    if let Some(cf_clearance) = cf_clearance {
+        client = client.default_headers(HeaderMap::from_iter([(
+            COOKIE,
+            format!("cf_clearance={cf_clearance}").try_into().unwrap(),
+        )]));
+    }
+
+    client.build().unwrap()
+}
+
+async fn run_command(command: Command) -> ExitCode {
+    match command {
+        Command::Verify { path } => {
+            if manifests::verify_library(&path).await {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Command::Check {
+            url,
+            directories,
+            connection,
+            download,
+            download_missing,
+        } => {
+            let Some(client) = connect(&connection).await else {
+                return ExitCode::FAILURE;
+            };
+
+            let is_complete = checks::check_album(
+                client,
+                &url,
+                &output_path(&directories),
+                directories.config(false, false),
+                download.config(&connection),
+                download.workers(),
+                Arc::from(download.playlist_format),
+                download_missing,
+            )
+            .await;
+
+            if is_complete {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+async fn read_urls(mut urls: Vec<String>, files: Vec<PathBuf>) -> Vec<String> {
+    for file in files {
+        let mut lines = BufReader::new(File::open(file).await.unwrap()).lines();
+
+        while let Some(line) = lines.next_line().await.unwrap() {
+            urls.push(line);
+        }
+    }
+
+    urls.reverse();
+    urls
+}
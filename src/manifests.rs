@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tokio::{fs, task};
+
+use crate::models::{AlbumManifest, ManifestFile, PlaylistFormat, Quality, Service};
+
+const MANIFEST_FILE_NAME: &str = ".lucida.json";
+
+/// serializes manifest updates, as singles from multiple albums can share a
+/// directory
+static MANIFEST_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// records the files and the URLs they were downloaded from in the manifests
+/// of their directories. only new files and files changed since the manifest
+/// was written are hashed
+pub async fn record_files(service: Service, files: Vec<(PathBuf, String, Option<Quality>)>) {
+    let mut directories = HashMap::<_, Vec<_>>::new();
+
+    for (path, url, quality) in files {
+        directories
+            .entry(path.parent().unwrap().to_path_buf())
+            .or_default()
+            .push((
+                path.file_name().unwrap().to_str().unwrap().to_owned(),
+                url,
+                quality,
+            ));
+    }
+
+    let _lock = MANIFEST_LOCK.lock().await;
+
+    for (directory, files) in directories {
+        // a corrupted manifest is left for verify to report
+        let Ok(manifest) = read_manifest(&directory).await else {
+            continue;
+        };
+
+        let mut manifest = manifest.unwrap_or_default();
+        let manifest_modified = manifest_modified(&directory).await;
+
+        for (name, url, quality) in files {
+            let path = directory.join(&name);
+            let metadata = fs::metadata(&path).await.unwrap();
+
+            // files downloaded by this run are always hashed, as they may have
+            // the same size as the files they replaced
+            let recorded_file = manifest.files.iter().find(|file| {
+                quality.is_none()
+                    && file.name == name
+                    && file.size == metadata.len()
+                    && manifest_modified.is_some_and(|manifest_modified| {
+                        metadata.modified().unwrap() < manifest_modified
+                    })
+            });
+
+            let (size, sha256) = match recorded_file {
+                Some(file) => (file.size, file.sha256.clone()),
+                None => hash_file(path).await,
+            };
+
+            // the quality of files downloaded by a previous run isn't known
+            let quality = quality.or_else(|| {
+                manifest
+                    .files
+                    .iter()
+                    .find(|file| file.name == name && file.sha256 == sha256)
+                    .and_then(|file| file.quality)
+            });
+
+            manifest.files.retain(|file| file.name != name);
+
+            manifest.files.push(ManifestFile {
+                name,
+                size,
+                sha256,
+                url,
+                service,
+                quality,
+            });
+        }
+
+        manifest.files.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        write_manifest(&directory, &manifest).await;
+    }
+}
+
+/// removes a file that no longer exists from the manifest of its directory
+pub async fn forget_file(path: &Path) {
+    let directory = path.parent().unwrap();
+    let name = path.file_name().unwrap().to_str().unwrap();
+
+    let _lock = MANIFEST_LOCK.lock().await;
+
+    let Ok(Some(mut manifest)) = read_manifest(directory).await else {
+        return;
+    };
+
+    manifest.files.retain(|file| file.name != name);
+    write_manifest(directory, &manifest).await;
+}
+
+/// moves the entries of moved or renamed files to the manifests of their new
+/// directories. all entries are taken out first, so files may take each
+/// other's names
+pub async fn move_files(moves: &[(PathBuf, PathBuf)]) {
+    let _lock = MANIFEST_LOCK.lock().await;
+
+    let mut manifests = HashMap::new();
+    let mut moved_files = Vec::new();
+
+    for (old_path, new_path) in moves {
+        let old_name = old_path.file_name().unwrap().to_str().unwrap();
+
+        let Some(Some(manifest)) =
+            cached_manifest(&mut manifests, old_path.parent().unwrap()).await
+        else {
+            continue;
+        };
+
+        if let Some(index) = manifest.files.iter().position(|file| file.name == old_name) {
+            let mut file = manifest.files.remove(index);
+            new_path
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .clone_into(&mut file.name);
+            moved_files.push((new_path.parent().unwrap(), file));
+        }
+    }
+
+    for (directory, file) in moved_files {
+        let Some(manifest) = cached_manifest(&mut manifests, directory).await else {
+            continue;
+        };
+
+        let manifest = manifest.get_or_insert_default();
+
+        manifest
+            .files
+            .retain(|existing_file| existing_file.name != file.name);
+        manifest.files.push(file);
+    }
+
+    for (directory, manifest) in manifests {
+        if let Ok(Some(mut manifest)) = manifest {
+            manifest.files.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            write_manifest(&directory, &manifest).await;
+        }
+    }
+}
+
+/// reads the manifest of the directory once. returns `None` when the manifest
+/// is corrupted
+async fn cached_manifest<'a>(
+    manifests: &'a mut HashMap<PathBuf, Result<Option<AlbumManifest>, ()>>,
+    directory: &Path,
+) -> Option<&'a mut Option<AlbumManifest>> {
+    if !manifests.contains_key(directory) {
+        let manifest = read_manifest(directory).await;
+        manifests.insert(directory.to_path_buf(), manifest);
+    }
+
+    manifests.get_mut(directory).unwrap().as_mut().ok()
+}
+
+/// walks the directory tree and compares every directory with its manifest.
+/// returns whether no problems were found
+pub async fn verify_library(path: &Path) -> bool {
+    let mut directories = vec![path.to_path_buf()];
+    let mut album_count = 0;
+    let mut problem_count = 0;
+
+    while let Some(directory) = directories.pop() {
+        let mut file_names = Vec::new();
+        let mut entries = fs::read_dir(&directory).await.unwrap();
+
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let file_type = entry.file_type().await.unwrap();
+
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() {
+                file_names.push(entry.file_name());
+            }
+        }
+
+        let manifest = match read_manifest(&directory).await {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => continue,
+            Err(()) => {
+                problem_count += 1;
+                continue;
+            }
+        };
+
+        album_count += 1;
+        problem_count += verify_directory(&directory, manifest, file_names).await;
+    }
+
+    tracing::info!("verified {album_count} directories, found {problem_count} problems");
+
+    problem_count == 0
+}
+
+async fn verify_directory(
+    directory: &Path,
+    manifest: AlbumManifest,
+    mut file_names: Vec<OsString>,
+) -> usize {
+    let mut problem_count = 0;
+
+    for file in manifest.files {
+        let path = directory.join(&file.name);
+
+        let Some(index) = file_names.iter().position(|name| *name == *file.name) else {
+            tracing::warn!("missing file {}", path.display());
+            problem_count += 1;
+            continue;
+        };
+
+        file_names.swap_remove(index);
+
+        let (size, sha256) = hash_file(path.clone()).await;
+
+        if size != file.size || sha256 != file.sha256 {
+            tracing::warn!("corrupted file {}", path.display());
+            problem_count += 1;
+        }
+    }
+
+    for file_name in file_names {
+        if file_name.to_str().is_some_and(is_ignored_file) {
+            continue;
+        }
+
+        tracing::warn!("extra file {}", directory.join(file_name).display());
+        problem_count += 1;
+    }
+
+    problem_count
+}
+
+/// manifests, playlist files and other files written by lucida-downloader
+/// itself aren't tracked
+pub fn is_ignored_file(file_name: &str) -> bool {
+    file_name.starts_with('.')
+        || file_name.rsplit_once('.').is_some_and(|(_, extension)| {
+            PlaylistFormat::value_variants()
+                .iter()
+                .any(|format| format.extension() == extension)
+        })
+}
+
+/// reads the manifest of the directory, if it has one. a manifest that cannot
+/// be parsed is reported and returned as an error
+async fn read_manifest(directory: &Path) -> Result<Option<AlbumManifest>, ()> {
+    let manifest_path = directory.join(MANIFEST_FILE_NAME);
+
+    let manifest = match fs::read(&manifest_path).await {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => panic!("failed to read {}: {err}", manifest_path.display()),
+    };
+
+    serde_json::from_slice(&manifest).map(Some).map_err(|err| {
+        tracing::warn!("corrupted manifest {}: {err}", manifest_path.display());
+    })
+}
+
+/// when the manifest of the directory was last written
+async fn manifest_modified(directory: &Path) -> Option<SystemTime> {
+    fs::metadata(directory.join(MANIFEST_FILE_NAME))
+        .await
+        .ok()?
+        .modified()
+        .ok()
+}
+
+async fn write_manifest(directory: &Path, manifest: &AlbumManifest) {
+    let manifest_path = directory.join(MANIFEST_FILE_NAME);
+    let part_path = manifest_path.with_added_extension("part");
+
+    fs::write(&part_path, serde_json::to_vec_pretty(manifest).unwrap())
+        .await
+        .unwrap();
+
+    fs::rename(part_path, manifest_path).await.unwrap();
+}
+
+async fn hash_file(path: PathBuf) -> (u64, String) {
+    task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path).unwrap();
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut file, &mut hasher).unwrap();
+
+        let sha256 = hasher
+            .finalize()
+            .iter()
+            .fold(String::new(), |mut sha256, byte| {
+                write!(sha256, "{byte:02x}").unwrap();
+                sha256
+            });
+
+        (size, sha256)
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignored_files() {
+        for file_name in [
+            ".lucida-manifest.json",
+            ".DS_Store",
+            "Album.m3u8",
+            "a.b.xspf",
+            "x.pls",
+        ] {
+            assert!(is_ignored_file(file_name), "{file_name}");
+        }
+
+        for file_name in [
+            "01 - Track.flac",
+            "cover.jpg",
+            "m3u8",
+            "Track.m3u",
+            "Track.pls.flac",
+        ] {
+            assert!(!is_ignored_file(file_name), "{file_name}");
+        }
+    }
+}
@@ -1,15 +1,55 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use clap::{Parser, ValueEnum};
+use bytes::Bytes;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use tokio::sync::mpsc::Receiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::filters::{self, TrackFilter};
+use crate::integrity::AudioFormat;
 
 pub const BASE_URL: &str = "https://lucida.to/";
 
+/// how long a processing status may stay unchanged by default
+const DEFAULT_STUCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// parses durations like "90s", "30m" or "2h". numbers without a unit are
+/// seconds
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (amount, unit) = value
+        .find(|char: char| !char.is_ascii_digit())
+        .map_or((value, ""), |index| value.split_at(index));
+
+    let amount = amount
+        .parse()
+        .map_err(|_| format!("invalid duration {value}"))?;
+
+    match unit {
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_mins(amount)),
+        "h" => Ok(Duration::from_hours(amount)),
+        _ => Err(format!(
+            "unknown unit {unit}, expected \"s\", \"m\" or \"h\""
+        )),
+    }
+}
+
 #[expect(clippy::struct_excessive_bools)]
 #[derive(Parser)]
-#[command(arg_required_else_help = true)]
+#[command(arg_required_else_help = true, args_conflicts_with_subcommands = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// URLs to download
     pub urls: Vec<String>,
 
@@ -17,31 +57,140 @@ pub struct Cli {
     #[arg(short, long)]
     pub file: Vec<PathBuf>,
 
-    /// custom path to download to
-    #[arg(short, long)]
-    pub output: Option<PathBuf>,
+    /// continue the queue saved in the output directory by a stopped run, along
+    /// with albums that timed out or couldn't be resolved
+    #[arg(long)]
+    pub resume: bool,
+
+    #[command(flatten)]
+    pub directories: DirectoryArgs,
 
     /// overwrite already downloaded files
     #[arg(long)]
     pub force: bool,
 
-    /// place all artist's singles in a "Singles" directory. their covers will
-    /// not be downloaded
+    /// download already downloaded tracks again and replace them when lucida
+    /// provides them in a better format or resolution
+    #[arg(long, conflicts_with = "force")]
+    pub upgrade: bool,
+
+    /// download the whole album when given a URL pointing to a single track
     #[arg(long)]
-    pub group_singles: bool,
+    pub expand_tracks: bool,
 
-    /// use "<album> (year)" or "(year) <album>" directory name
-    #[arg(value_enum, long)]
-    pub album_year: Option<AlbumYear>,
+    #[command(flatten)]
+    pub filter: FilterArgs,
 
-    /// use "<artist> - <album>" format instead of nested "<artist>/<album>"
-    /// directories
+    /// download playlist tracks into their album directories. the playlist
+    /// directory will only contain playlist files pointing at them
+    #[arg(long, conflicts_with = "sync")]
+    pub playlist_library: bool,
+
+    /// remove album directories created by the run that end up without audio
+    /// files, e.g. when all of their tracks were unavailable
     #[arg(long)]
-    pub flatten_directories: bool,
+    pub remove_empty_albums: bool,
 
-    /// country to use accounts from
-    #[arg(long, default_value_t = String::from("auto"))]
-    pub country: String,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+
+    /// maximum download speed across all downloads in bytes per second, with an
+    /// optional K, M or G suffix, e.g. "5M"
+    #[arg(long, value_name = "RATE")]
+    pub limit_rate: Option<ByteRate>,
+
+    /// download speed during a time of day instead of --limit-rate, as
+    /// "<start>-<end>=<rate>" in local time, e.g. "00:00-07:00=unlimited" or
+    /// "09:00-17:00=1M". can be given multiple times
+    #[arg(long, value_name = "SCHEDULE")]
+    pub limit_schedule: Vec<RateWindow>,
+
+    /// skip albums that take longer than this to download, e.g. "30m". units
+    /// are "s", "m" and "h"
+    #[arg(long, value_parser = parse_duration)]
+    pub album_timeout: Option<Duration>,
+
+    /// amount of albums to download simultaneously
+    #[arg(long, default_value_t = 1)]
+    pub album_workers: usize,
+
+    /// amount of queued albums to resolve ahead of the album workers, which
+    /// are resolved simultaneously
+    #[arg(long, default_value_t = 2)]
+    pub prefetch_albums: usize,
+
+    /// skip downloading tracks in the album
+    #[arg(long)]
+    pub skip_tracks: bool,
+
+    /// skip downloading album cover
+    #[arg(long)]
+    pub skip_cover: bool,
+
+    /// skip writing playlist files for downloaded playlists
+    #[arg(long)]
+    pub skip_playlist: bool,
+
+    /// synchronize already downloaded playlists with their current track list
+    #[arg(long)]
+    pub sync: bool,
+
+    /// what to do with tracks removed from a synchronized playlist. "trash"
+    /// moves them to a ".trash" directory inside the playlist directory
+    #[arg(value_enum, long, default_value_t = RemovedTracks::Keep)]
+    pub sync_removed: RemovedTracks,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+}
+
+#[derive(Args)]
+pub struct FilterArgs {
+    /// only download tracks with these numbers, e.g. "1-3,7"
+    #[arg(long, value_name = "RANGES", value_parser = filters::parse_track_ranges)]
+    pub tracks: Vec<Vec<RangeInclusive<u32>>>,
+
+    /// only download tracks with titles matching this regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub include_title: Vec<Regex>,
+
+    /// skip tracks with titles matching this regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub exclude_title: Vec<Regex>,
+
+    /// only download tracks with an artist matching this regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub include_artist: Vec<Regex>,
+
+    /// skip tracks with an artist matching this regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    pub exclude_artist: Vec<Regex>,
+}
+
+impl FilterArgs {
+    pub fn filter(self) -> TrackFilter {
+        TrackFilter {
+            track_urls: Vec::new(),
+            track_numbers: self.tracks.into_iter().flatten().collect(),
+            include_titles: self.include_title,
+            exclude_titles: self.exclude_title,
+            include_artists: self.include_artist,
+            exclude_artists: self.exclude_artist,
+        }
+    }
+}
+
+#[expect(clippy::struct_excessive_bools)]
+#[derive(Args)]
+pub struct DownloadArgs {
+    /// qualities to request, in order of preference. the next one is used when
+    /// lucida refuses to provide a track in the previous one
+    #[arg(value_enum, long, default_values_t = [Quality::Original])]
+    pub quality: Vec<Quality>,
+
+    /// request files in a format compatible with more players
+    #[arg(long)]
+    pub compat: bool,
 
     /// disable metadata embedding by lucida
     #[arg(long)]
@@ -51,21 +200,107 @@ pub struct Cli {
     #[arg(long)]
     pub private: bool,
 
-    /// amount of albums to download simultaneously
-    #[arg(long, default_value_t = 1)]
-    pub album_workers: usize,
+    /// try to download tracks that seem to be unavailable
+    #[arg(long)]
+    pub attempt_unavailable: bool,
 
-    /// amount of tracks to download simultaneously for each album
+    /// seconds a processing status may stay unchanged before the track is
+    /// requested again, as "<status>=<seconds>", e.g. "queued=300". statuses
+    /// are "queued", "processing" and "uploading". defaults to 30 seconds
+    #[arg(long, value_name = "STATUS=SECONDS")]
+    pub stuck_timeout: Vec<StuckTimeout>,
+
+    /// amount of tracks to have lucida process simultaneously for each album
     #[arg(long, default_value_t = 4)]
     pub track_workers: usize,
 
-    /// skip downloading tracks in the album
+    /// amount of processed tracks to download simultaneously for each album
+    #[arg(long, default_value_t = 2)]
+    pub download_workers: usize,
+
+    /// playlist file formats to write alongside downloaded playlists
+    #[arg(value_enum, long, default_values_t = [PlaylistFormat::M3u8])]
+    pub playlist_format: Vec<PlaylistFormat>,
+}
+
+impl DownloadArgs {
+    pub fn config(&self, connection: &ConnectionArgs) -> DownloadConfig {
+        DownloadConfig {
+            country: connection.country[0].clone(),
+            accounts: connection.accounts(),
+            qualities: self.quality.clone(),
+            compat: self.compat,
+            metadata: !self.no_metadata,
+            private: self.private,
+            attempt_unavailable: self.attempt_unavailable,
+            stuck_timeouts: self.stuck_timeout.clone(),
+        }
+    }
+
+    pub const fn workers(&self) -> WorkerConfig {
+        WorkerConfig {
+            track_workers: self.track_workers,
+            download_workers: self.download_workers,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct DirectoryArgs {
+    /// custom path to download to
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// place all artist's singles in a "Singles" directory. their covers will
+    /// not be downloaded
     #[arg(long)]
-    pub skip_tracks: bool,
+    pub group_singles: bool,
 
-    /// skip downloading album cover
+    /// use "<album> (year)" or "(year) <album>" directory name
+    #[arg(value_enum, long)]
+    pub album_year: Option<AlbumYear>,
+
+    /// append the audio format of the first downloaded track to album
+    /// directory names, e.g. "<album> [FLAC 24-96]"
     #[arg(long)]
-    pub skip_cover: bool,
+    pub album_format: bool,
+
+    /// use "<artist> - <album>" format instead of nested "<artist>/<album>"
+    /// directories
+    #[arg(long)]
+    pub flatten_directories: bool,
+}
+
+impl DirectoryArgs {
+    pub const fn config(
+        &self,
+        playlist_library: bool,
+        remove_empty_albums: bool,
+    ) -> DirectoryConfig {
+        DirectoryConfig {
+            group_singles: self.group_singles,
+            album_year: self.album_year,
+            album_format: self.album_format,
+            flatten_directories: self.flatten_directories,
+            playlist_library,
+            remove_empty_albums,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ConnectionArgs {
+    /// countries to use accounts from in order of preference, e.g. "us,gb,de".
+    /// the next one is used when a track isn't available in the previous one
+    #[arg(long, value_delimiter = ',', default_values_t = [String::from("auto")])]
+    pub country: Vec<String>,
+
+    /// lucida account to use instead of the countries, as "<type>:<id>", or as
+    /// "<service>=<type>:<id>" to only use it for URLs of that service, e.g.
+    /// "qobuz=account:1234". types are "country" and "account". can be given
+    /// multiple times to fall back to the next account
+    #[arg(long, value_name = "ACCOUNT")]
+    pub account: Vec<AccountSelection>,
 
     /// set the `cf_clearance` cookie and the User-Agent header if Cloudflare is
     /// blocking your requests
@@ -77,14 +312,392 @@ pub struct Cli {
     pub user_agent: Option<String>,
 }
 
+impl ConnectionArgs {
+    /// the accounts to download tracks with. the countries are used for
+    /// services without their own accounts if no other accounts are given
+    pub fn accounts(&self) -> Vec<AccountSelection> {
+        let (mut accounts, default_accounts) = self
+            .account
+            .iter()
+            .cloned()
+            .partition::<Vec<_>, _>(|account| account.service.is_some());
+
+        if default_accounts.is_empty() {
+            accounts.extend(self.country.iter().map(|country| AccountSelection {
+                service: None,
+                r#type: AccountType::Country,
+                id: country.clone(),
+            }));
+        } else {
+            accounts.extend(default_accounts);
+        }
+
+        accounts
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// compare downloaded files with their manifests and report missing,
+    /// corrupted and extra files
+    Verify {
+        /// directory to verify recursively
+        path: PathBuf,
+    },
+    /// compare a downloaded album with its current track list and report
+    /// missing, duplicated and renamed tracks
+    Check {
+        /// URL of the album to check
+        url: String,
+
+        #[command(flatten)]
+        directories: DirectoryArgs,
+
+        #[command(flatten)]
+        connection: ConnectionArgs,
+
+        #[command(flatten)]
+        download: Box<DownloadArgs>,
+
+        /// download missing tracks without asking
+        #[arg(long)]
+        download_missing: bool,
+    },
+}
+
+/// what to do with files that are already downloaded
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExistingFiles {
+    Keep,
+    Upgrade,
+    Overwrite,
+}
+
+impl ExistingFiles {
+    pub const fn new(force: bool, upgrade: bool) -> Self {
+        if force {
+            Self::Overwrite
+        } else if upgrade {
+            Self::Upgrade
+        } else {
+            Self::Keep
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountType {
+    Country,
+    Account,
+}
+
+#[derive(Clone)]
+pub struct AccountSelection {
+    /// the account is only used for URLs of this service
+    pub service: Option<Service>,
+    pub r#type: AccountType,
+    pub id: String,
+}
+
+impl FromStr for AccountSelection {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (service, account) = match value.split_once('=') {
+            Some((service, account)) => (
+                Some(
+                    Service::from_str(service, true)
+                        .map_err(|_| format!("unknown service {service}"))?,
+                ),
+                account,
+            ),
+            None => (None, value),
+        };
+
+        let Some((r#type, id)) = account.split_once(':') else {
+            return Err(format!("expected <type>:<id>, got {account}"));
+        };
+
+        let r#type = match r#type {
+            "country" => AccountType::Country,
+            "account" => AccountType::Account,
+            _ => return Err(format!("unknown account type {type}")),
+        };
+
+        Ok(Self {
+            service,
+            r#type,
+            id: id.to_owned(),
+        })
+    }
+}
+
+impl Display for AccountSelection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.r#type {
+            AccountType::Country => write!(f, "country {}", self.id),
+            AccountType::Account => write!(f, "account {}", self.id),
+        }
+    }
+}
+
+/// download speed in bytes per second
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ByteRate(pub u32);
+
+impl FromStr for ByteRate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (amount, multiplier) = match value.char_indices().last() {
+            Some((index, 'k' | 'K')) => (&value[..index], 1 << 10),
+            Some((index, 'm' | 'M')) => (&value[..index], 1 << 20),
+            Some((index, 'g' | 'G')) => (&value[..index], 1 << 30),
+            _ => (value, 1),
+        };
+
+        amount
+            .parse::<u32>()
+            .ok()
+            .and_then(|amount| amount.checked_mul(multiplier))
+            .filter(|&rate| rate > 0)
+            .map(Self)
+            .ok_or_else(|| format!("invalid rate {value}"))
+    }
+}
+
+impl Display for ByteRate {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            rate if rate % (1 << 20) == 0 => write!(f, "{}M/s", rate >> 20),
+            rate if rate % (1 << 10) == 0 => write!(f, "{}K/s", rate >> 10),
+            rate => write!(f, "{rate}B/s"),
+        }
+    }
+}
+
+/// download speed during a time of day. `None` means unlimited
+#[derive(Clone, Copy)]
+pub struct RateWindow {
+    /// minutes since midnight
+    pub start: u16,
+    pub end: u16,
+    pub rate: Option<ByteRate>,
+}
+
+impl RateWindow {
+    /// whether the window contains the minute of the day. windows ending
+    /// before they start wrap around midnight
+    pub const fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for RateWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((start, end, rate)) = value
+            .split_once('=')
+            .and_then(|(window, rate)| Some((window.split_once('-')?, rate)))
+            .map(|((start, end), rate)| (start, end, rate))
+        else {
+            return Err(format!("expected <start>-<end>=<rate>, got {value}"));
+        };
+
+        let parse_time = |time: &str| {
+            time.split_once(':')
+                .and_then(|(hours, minutes)| Some((hours.parse().ok()?, minutes.parse().ok()?)))
+                .filter(|&(hours, minutes): &(u16, u16)| {
+                    (hours < 24 && minutes < 60) || (hours, minutes) == (24, 0)
+                })
+                .map(|(hours, minutes)| hours * 60 + minutes)
+                .ok_or_else(|| format!("invalid time {time}, expected <hours>:<minutes>"))
+        };
+
+        Ok(Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            rate: match rate {
+                "unlimited" => None,
+                rate => Some(rate.parse()?),
+            },
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct StuckTimeout {
+    pub status: ProcessingStatus,
+    pub timeout: Duration,
+}
+
+impl FromStr for StuckTimeout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((status, seconds)) = value.split_once('=') else {
+            return Err(format!("expected <status>=<seconds>, got {value}"));
+        };
+
+        let status = match status {
+            "queued" => ProcessingStatus::Queued,
+            "processing" => ProcessingStatus::Processing,
+            "uploading" => ProcessingStatus::Uploading,
+            _ => return Err(format!("unknown processing status {status}")),
+        };
+
+        let seconds = seconds
+            .parse()
+            .map_err(|_| format!("invalid amount of seconds {seconds}"))?;
+
+        Ok(Self {
+            status,
+            timeout: Duration::from_secs(seconds),
+        })
+    }
+}
+
 #[derive(Clone, Copy, ValueEnum)]
 pub enum AlbumYear {
     Append,
     Prepend,
 }
 
+#[derive(Clone, Copy, ValueEnum, Serialize, Deserialize)]
+pub enum Quality {
+    #[serde(rename = "original")]
+    Original,
+    #[value(name = "flac-16")]
+    #[serde(rename = "flac-16")]
+    Flac16,
+    #[value(name = "mp3-320")]
+    #[serde(rename = "mp3-320")]
+    Mp3320,
+    #[value(name = "mp3-256")]
+    #[serde(rename = "mp3-256")]
+    Mp3256,
+    #[value(name = "mp3-128")]
+    #[serde(rename = "mp3-128")]
+    Mp3128,
+    #[value(name = "ogg-320")]
+    #[serde(rename = "ogg-320")]
+    Ogg320,
+    #[value(name = "ogg-256")]
+    #[serde(rename = "ogg-256")]
+    Ogg256,
+    #[value(name = "ogg-128")]
+    #[serde(rename = "ogg-128")]
+    Ogg128,
+}
+
+impl Quality {
+    /// the value of the `downscale` field lucida expects
+    pub const fn downscale(self) -> &'static str {
+        match self {
+            Self::Original => "original",
+            Self::Flac16 => "flac-16",
+            Self::Mp3320 => "mp3-320",
+            Self::Mp3256 => "mp3-256",
+            Self::Mp3128 => "mp3-128",
+            Self::Ogg320 => "ogg-320",
+            Self::Ogg256 => "ogg-256",
+            Self::Ogg128 => "ogg-128",
+        }
+    }
+
+    /// the best format lucida provides in the quality, unknown for the original
+    /// quality. the sample rate of downscaled FLAC is kept, so it's assumed to
+    /// be the highest
+    pub const fn best_format(self) -> Option<AudioFormat> {
+        match self {
+            Self::Original => None,
+            Self::Flac16 => Some(AudioFormat::Flac {
+                bits_per_sample: 16,
+                sample_rate: u32::MAX,
+            }),
+            Self::Mp3320 => Some(AudioFormat::Mp3 {
+                bitrate: Some(320_000),
+            }),
+            Self::Mp3256 => Some(AudioFormat::Mp3 {
+                bitrate: Some(256_000),
+            }),
+            Self::Mp3128 => Some(AudioFormat::Mp3 {
+                bitrate: Some(128_000),
+            }),
+            Self::Ogg320 => Some(AudioFormat::Vorbis {
+                bitrate: Some(320_000),
+            }),
+            Self::Ogg256 => Some(AudioFormat::Vorbis {
+                bitrate: Some(256_000),
+            }),
+            Self::Ogg128 => Some(AudioFormat::Vorbis {
+                bitrate: Some(128_000),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PlaylistFormat {
+    M3u8,
+    Xspf,
+    Pls,
+}
+
+impl PlaylistFormat {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::M3u8 => "m3u8",
+            Self::Xspf => "xspf",
+            Self::Pls => "pls",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum RemovedTracks {
+    Keep,
+    Delete,
+    Trash,
+}
+
 pub enum ResolveAlbumError {
-    ArtistUrl { name: String },
+    /// lucida failed to resolve the page, which may succeed when tried again
+    Unresolved,
+    ArtistUrl {
+        name: String,
+    },
+}
+
+pub enum RequestTrackError {
+    Stopped,
+    TokenExpired,
+    /// lucida refused the track with every fallback
+    Unavailable(UnavailableReason),
+}
+
+/// the tokens lucida requires to download the tracks of a page. they are
+/// refreshed by resolving the page again once they expire
+pub struct PageTokens {
+    pub url: String,
+    pub expand_tracks: bool,
+    pub expiry: u64,
+    /// refreshed tokens of the tracks by their URL. tracks without an entry
+    /// use the tokens they were resolved with
+    pub tracks: HashMap<String, (Option<String>, Option<String>)>,
+}
+
+/// an album resolved ahead of downloading it
+pub struct ResolvedAlbum {
+    pub info: AlbumInfo,
+    pub service: Service,
+    pub tokens: PageTokens,
 }
 
 pub enum Availability {
@@ -93,19 +706,89 @@ pub enum Availability {
     Unavailable,
 }
 
+#[expect(clippy::struct_excessive_bools)]
 #[derive(Clone)]
 pub struct DownloadConfig {
+    /// country to resolve pages from
     pub country: String,
+    pub accounts: Vec<AccountSelection>,
+    pub qualities: Vec<Quality>,
+    pub compat: bool,
     pub metadata: bool,
     pub private: bool,
+    pub attempt_unavailable: bool,
+    pub stuck_timeouts: Vec<StuckTimeout>,
+}
+
+#[expect(clippy::struct_excessive_bools)]
+#[derive(Clone, Copy)]
+pub struct DirectoryConfig {
+    pub group_singles: bool,
+    pub album_year: Option<AlbumYear>,
+    pub album_format: bool,
+    pub flatten_directories: bool,
+    pub playlist_library: bool,
+    pub remove_empty_albums: bool,
+}
+
+impl DownloadConfig {
+    /// the accounts to try in order for tracks of the service
+    pub fn accounts(&self, service: Service) -> Vec<&AccountSelection> {
+        let service_accounts = self
+            .accounts
+            .iter()
+            .filter(|account| account.service == Some(service))
+            .collect::<Vec<_>>();
+
+        if service_accounts.is_empty() {
+            self.accounts
+                .iter()
+                .filter(|account| account.service.is_none())
+                .collect()
+        } else {
+            service_accounts
+        }
+    }
+
+    /// how long the processing status may stay unchanged before the track is
+    /// considered stuck
+    pub fn stuck_timeout(&self, status: ProcessingStatus) -> Duration {
+        self.stuck_timeouts
+            .iter()
+            .rfind(|timeout| timeout.status == status)
+            .map_or(DEFAULT_STUCK_TIMEOUT, |timeout| timeout.timeout)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct WorkerConfig {
+    pub track_workers: usize,
+    pub download_workers: usize,
 }
 
 #[derive(Clone, Copy)]
 pub struct SkipConfig {
     pub tracks: bool,
     pub cover: bool,
+    pub playlist: bool,
+}
+
+pub struct DownloadedTrack {
+    pub track_number: Option<u32>,
+    pub track: Track,
+    pub path: PathBuf,
+    pub quality: Option<Quality>,
 }
 
+#[derive(Clone)]
+pub struct TrackLocation {
+    pub directory: Arc<PathBuf>,
+    pub track_number: Option<u32>,
+    pub track_count: u32,
+    pub is_grouped_single: bool,
+}
+
+#[derive(Clone)]
 pub struct AlbumInfo {
     pub title: String,
     pub release_year: Option<u16>,
@@ -113,6 +796,7 @@ pub struct AlbumInfo {
     pub artist_name: String,
     pub tracks: Vec<(Option<u32>, Track)>,
     pub track_count: u32,
+    pub is_playlist: bool,
 }
 
 impl AlbumInfo {
@@ -141,6 +825,7 @@ impl AlbumInfo {
                     .rev()
                     .collect(),
                 track_count,
+                is_playlist: false,
             }),
             Info::Playlist {
                 title,
@@ -159,6 +844,7 @@ impl AlbumInfo {
                     .rev()
                     .collect(),
                 track_count,
+                is_playlist: true,
             }),
             Info::Track {
                 url,
@@ -167,7 +853,9 @@ impl AlbumInfo {
                 artists,
                 mut album,
                 release_date,
+                duration_ms,
                 producers,
+                flags,
             } => Ok(Self {
                 title: album
                     .as_ref()
@@ -198,18 +886,52 @@ impl AlbumInfo {
                         title,
                         url,
                         artists,
+                        duration_ms,
+                        number: None,
+                        album: None,
                         producers,
+                        flags,
                         csrf: token,
                         csrf_fallback: None,
                     },
                 )],
                 track_count: album.and_then(|album| album.track_count).unwrap_or(1),
+                is_playlist: false,
             }),
             Info::Artist { name } => Err(ResolveAlbumError::ArtistUrl { name }),
         }
     }
 }
 
+#[derive(Default, Serialize, Deserialize)]
+pub struct AlbumManifest {
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub url: String,
+    pub service: Service,
+    pub quality: Option<Quality>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlaylistManifest {
+    pub tracks: Vec<PlaylistManifestTrack>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlaylistManifestTrack {
+    pub url: String,
+    pub title: String,
+    /// manifests written before playlist libraries only have file names
+    #[serde(alias = "file_name")]
+    pub path: String,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PageData {
@@ -249,7 +971,10 @@ pub enum Info {
         album: Option<Album>,
         #[serde(with = "time::serde::rfc3339::option")]
         release_date: Option<OffsetDateTime>,
+        duration_ms: Option<u64>,
         producers: Option<Vec<String>>,
+        #[serde(flatten)]
+        flags: TrackFlags,
     },
     #[serde(rename_all = "camelCase")]
     Artist { name: String },
@@ -265,20 +990,81 @@ pub struct Artist {
     pub name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Track {
     pub title: String,
     pub url: String,
     pub artists: Vec<Artist>,
+    pub duration_ms: Option<u64>,
+    #[serde(rename = "trackNumber")]
+    pub number: Option<u32>,
+    pub album: Option<TrackAlbum>,
     pub producers: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub flags: TrackFlags,
     pub csrf: Option<String>,
     pub csrf_fallback: Option<String>,
 }
 
+impl Track {
+    /// checks whether lucida will be able to download the track
+    pub const fn availability(&self, service: Service) -> Result<(), UnavailableReason> {
+        match (self.flags.streamable, self.flags.purchasable) {
+            (Some(false), Some(true)) => Err(UnavailableReason::PurchaseOnly),
+            (Some(false), _) => Err(UnavailableReason::NotStreamable),
+            // Qobuz doesn't return producers for tracks that aren't released yet
+            (None, _) if matches!(service, Service::Qobuz) && self.producers.is_none() => {
+                Err(UnavailableReason::NotReleased)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// availability flags of the track, when the service provides them
+#[derive(Clone, Copy, Deserialize)]
+pub struct TrackFlags {
+    pub streamable: Option<bool>,
+    pub purchasable: Option<bool>,
+}
+
+#[derive(Clone, Copy)]
+pub enum UnavailableReason {
+    NotStreamable,
+    PurchaseOnly,
+    NotReleased,
+    RegionRestricted,
+    QualityRefused,
+}
+
+impl Display for UnavailableReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotStreamable => "not streamable",
+            Self::PurchaseOnly => "only available for purchase",
+            Self::NotReleased => "not released yet",
+            Self::RegionRestricted => "not available in the regions of the accounts",
+            Self::QualityRefused => "not available in the requested qualities",
+        })
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackAlbum {
+    pub title: String,
+    #[serde(default)]
+    pub artists: Vec<Artist>,
+    pub track_count: Option<u32>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub release_date: Option<OffsetDateTime>,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Album {
+    pub url: Option<String>,
     pub title: String,
     pub cover_artwork: Vec<CoverArtwork>,
     pub artists: Vec<Artist>,
@@ -287,7 +1073,7 @@ pub struct Album {
     pub release_date: OffsetDateTime,
 }
 
-#[derive(Clone, Copy, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Service {
     Qobuz,
@@ -313,10 +1099,10 @@ pub struct TrackDownloadRequest<'a> {
 #[derive(Serialize)]
 pub struct Account<'a> {
     pub id: &'a str,
-    pub r#type: &'static str,
+    pub r#type: AccountType,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Copy, Serialize)]
 pub struct Token<'a> {
     pub expiry: u64,
     pub primary: Option<&'a str>,
@@ -335,14 +1121,172 @@ pub enum TrackDownloadResult {
     Error { error: String },
 }
 
+/// the audio of a track being downloaded
+pub struct TrackStream {
+    pub chunks: Receiver<Result<Bytes, ()>>,
+    pub mime_type: String,
+    /// length of the whole file, including the part downloaded before when
+    /// the download was resumed
+    pub content_length: Option<u64>,
+    pub is_resumed: bool,
+}
+
 #[derive(Deserialize)]
 pub struct TrackDownload {
     pub handoff: String,
     pub server: String,
 }
 
+/// a track processed by lucida, waiting for a download worker
+pub struct TrackHandoff {
+    pub track_download: TrackDownload,
+    pub quality: Quality,
+    pub directory: Arc<PathBuf>,
+    pub file_stem: String,
+    pub existing_path: Option<PathBuf>,
+    /// cancels the track, a child of the token of the album
+    pub cancel: CancellationToken,
+}
+
+pub enum ProcessedTrack {
+    /// the track is already downloaded
+    Existing(PathBuf),
+    Ready(TrackHandoff),
+}
+
 #[derive(Debug, Deserialize)]
 pub struct TrackDownloadStatus {
-    pub status: String,
+    pub status: ProcessingStatus,
     pub message: String,
 }
+
+/// state of a track being prepared by lucida
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessingStatus {
+    Queued,
+    Processing,
+    Uploading,
+    Completed,
+    Error,
+    #[serde(other)]
+    Unknown,
+}
+
+impl Display for ProcessingStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Queued => "queued",
+            Self::Processing => "processing",
+            Self::Uploading => "uploading",
+            Self::Completed => "completed",
+            Self::Error => "error",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_rate_window(value: &str) -> Result<(u16, u16, Option<u32>), String> {
+        value
+            .parse::<RateWindow>()
+            .map(|window| (window.start, window.end, window.rate.map(|rate| rate.0)))
+    }
+
+    fn rate_window(start: u16, end: u16) -> RateWindow {
+        RateWindow {
+            start,
+            end,
+            rate: None,
+        }
+    }
+
+    #[test]
+    fn byte_rates() {
+        let rates = [
+            ("500", 500),
+            ("512k", 512 << 10),
+            ("2M", 2 << 20),
+            ("1g", 1 << 30),
+        ];
+
+        for (value, rate) in rates {
+            assert_eq!(value.parse::<ByteRate>().map(|rate| rate.0), Ok(rate));
+        }
+
+        for value in [
+            "",
+            "0",
+            "0k",
+            "k",
+            "-1",
+            "1.5M",
+            "4G",
+            "5000000000",
+            "10 M",
+            "10MB",
+        ] {
+            assert!(value.parse::<ByteRate>().is_err(), "{value:?}");
+        }
+
+        assert_eq!(ByteRate(2 << 20).to_string(), "2M/s");
+        assert_eq!(ByteRate(512 << 10).to_string(), "512K/s");
+        assert_eq!(ByteRate(1500).to_string(), "1500B/s");
+    }
+
+    #[test]
+    fn rate_windows() {
+        assert_eq!(
+            parse_rate_window("08:00-18:30=1M"),
+            Ok((480, 1110, Some(1 << 20)))
+        );
+
+        assert_eq!(
+            parse_rate_window("22:00-06:00=unlimited"),
+            Ok((1320, 360, None))
+        );
+
+        assert_eq!(
+            parse_rate_window("00:00-24:00=500k"),
+            Ok((0, 1440, Some(500 << 10)))
+        );
+
+        for value in [
+            "",
+            "08:00-18:00",
+            "08:00=1M",
+            "8-18=1M",
+            "08:00-24:01=1M",
+            "25:00-06:00=1M",
+            "08:60-18:00=1M",
+            "08:00-18:00=0",
+            "08:00-18:00=fast",
+            "-1:00-18:00=1M",
+        ] {
+            assert!(value.parse::<RateWindow>().is_err(), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn rate_window_minutes() {
+        let window = rate_window(480, 1110);
+        assert!(window.contains(480));
+        assert!(window.contains(1109));
+        assert!(!window.contains(1110));
+        assert!(!window.contains(479));
+
+        // wraps around midnight
+        let window = rate_window(1320, 360);
+        assert!(window.contains(1320));
+        assert!(window.contains(0));
+        assert!(window.contains(359));
+        assert!(!window.contains(360));
+        assert!(!window.contains(720));
+
+        assert!(rate_window(0, 1440).contains(1439));
+        assert!(!rate_window(600, 600).contains(600));
+    }
+}
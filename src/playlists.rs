@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use tokio::fs;
+
+use crate::models::{
+    DownloadedTrack, PlaylistFormat, PlaylistManifest, PlaylistManifestTrack, RemovedTracks, Track,
+};
+use crate::{manifests, text_utils};
+
+const MANIFEST_FILE_NAME: &str = ".lucida-playlist.json";
+const TRASH_DIRECTORY_NAME: &str = ".trash";
+
+pub struct PlaylistEntry<'a> {
+    pub track: &'a Track,
+    pub path: String,
+}
+
+pub async fn save_playlist(
+    directory: &Path,
+    title: &str,
+    formats: &[PlaylistFormat],
+    mut tracks: Vec<DownloadedTrack>,
+) {
+    tracks.sort_unstable_by_key(|track| track.track_number);
+
+    let entries = tracks
+        .iter()
+        .map(|track| PlaylistEntry {
+            track: &track.track,
+            path: text_utils::relative_path(directory, &track.path),
+        })
+        .collect::<Vec<_>>();
+
+    write_manifest(directory, &entries).await;
+
+    for &format in formats {
+        write_playlist(directory, title, format, &entries).await;
+    }
+}
+
+pub async fn sync_playlist(
+    directory: &Path,
+    tracks: &[(Option<u32>, Track)],
+    track_count: u32,
+    removed_tracks: RemovedTracks,
+) {
+    let manifest_path = directory.join(MANIFEST_FILE_NAME);
+
+    let manifest = match fs::read(&manifest_path).await {
+        Ok(manifest) => serde_json::from_slice::<PlaylistManifest>(&manifest).unwrap(),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            tracing::info!("playlist has no manifest, downloading all tracks");
+            return;
+        }
+        Err(err) => panic!("failed to read {}: {err}", manifest_path.display()),
+    };
+
+    let remote_tracks = tracks
+        .iter()
+        .map(|(track_number, track)| (track.url.as_str(), (*track_number, track)))
+        .collect::<HashMap<_, _>>();
+
+    let mut renamed_tracks = Vec::new();
+    let mut trashed_tracks = Vec::new();
+    let mut removed_count = 0;
+
+    for local_track in manifest.tracks {
+        // tracks outside of the playlist directory belong to the library and
+        // are left alone
+        if local_track.path.contains('/') {
+            continue;
+        }
+
+        let local_path = directory.join(&local_track.path);
+
+        if !fs::try_exists(&local_path).await.unwrap() {
+            continue;
+        }
+
+        if let Some(&(track_number, track)) = remote_tracks.get(local_track.url.as_str()) {
+            let file_stem = text_utils::format_track_stem(track, track_number, track_count, false);
+
+            let file_name = match local_track.path.rsplit_once('.') {
+                Some((_, extension)) => format!("{file_stem}.{extension}"),
+                None => file_stem,
+            };
+
+            if file_name != local_track.path {
+                renamed_tracks.push((local_track.path, file_name));
+            }
+
+            continue;
+        }
+
+        removed_count += 1;
+
+        match removed_tracks {
+            RemovedTracks::Keep => {
+                tracing::info!("keeping removed track {}", local_track.title);
+            }
+            RemovedTracks::Delete => {
+                tracing::info!("deleting removed track {}", local_track.title);
+                fs::remove_file(&local_path).await.unwrap();
+                manifests::forget_file(&local_path).await;
+            }
+            RemovedTracks::Trash => {
+                tracing::info!("moving removed track {} to trash", local_track.title);
+                let trash_path = directory.join(TRASH_DIRECTORY_NAME);
+                fs::create_dir_all(&trash_path).await.unwrap();
+                let trashed_path = trash_path.join(local_track.path);
+                fs::rename(&local_path, &trashed_path).await.unwrap();
+                trashed_tracks.push((local_path, trashed_path));
+            }
+        }
+    }
+
+    // renaming in two passes keeps tracks that swapped positions from
+    // overwriting each other
+    for (old_file_name, _) in &renamed_tracks {
+        fs::rename(
+            directory.join(old_file_name),
+            directory.join(format!("{old_file_name}.renumber")),
+        )
+        .await
+        .unwrap();
+    }
+
+    for (old_file_name, new_file_name) in &renamed_tracks {
+        tracing::info!("renaming {old_file_name} to {new_file_name}");
+
+        fs::rename(
+            directory.join(format!("{old_file_name}.renumber")),
+            directory.join(new_file_name),
+        )
+        .await
+        .unwrap();
+    }
+
+    // the manifest follows the files, so they aren't reported as missing
+    manifests::move_files(
+        &renamed_tracks
+            .iter()
+            .map(|(old_file_name, new_file_name)| {
+                (directory.join(old_file_name), directory.join(new_file_name))
+            })
+            .chain(trashed_tracks)
+            .collect::<Vec<_>>(),
+    )
+    .await;
+
+    tracing::info!(
+        "synchronized playlist: {removed_count} tracks removed, {} tracks renumbered",
+        renamed_tracks.len()
+    );
+}
+
+async fn write_manifest(directory: &Path, entries: &[PlaylistEntry<'_>]) {
+    let manifest = PlaylistManifest {
+        tracks: entries
+            .iter()
+            .map(|entry| PlaylistManifestTrack {
+                url: entry.track.url.clone(),
+                title: entry.track.title.clone(),
+                path: entry.path.clone(),
+            })
+            .collect(),
+    };
+
+    let manifest_path = directory.join(MANIFEST_FILE_NAME);
+    let part_path = manifest_path.with_added_extension("part");
+
+    fs::write(&part_path, serde_json::to_vec_pretty(&manifest).unwrap())
+        .await
+        .unwrap();
+
+    fs::rename(part_path, manifest_path).await.unwrap();
+}
+
+async fn write_playlist(
+    directory: &Path,
+    title: &str,
+    format: PlaylistFormat,
+    entries: &[PlaylistEntry<'_>],
+) {
+    let playlist_path = directory.join(format!(
+        "{}.{}",
+        text_utils::sanitize_file_name(title),
+        format.extension()
+    ));
+
+    tracing::info!("writing playlist file {}", playlist_path.display());
+
+    let contents = match format {
+        PlaylistFormat::M3u8 => format_m3u8(entries),
+        PlaylistFormat::Xspf => format_xspf(title, entries),
+        PlaylistFormat::Pls => format_pls(entries),
+    };
+
+    let part_path = playlist_path.with_added_extension("part");
+    fs::write(&part_path, contents).await.unwrap();
+    fs::rename(part_path, playlist_path).await.unwrap();
+}
+
+fn format_m3u8(entries: &[PlaylistEntry]) -> String {
+    let mut playlist = String::from("#EXTM3U\n");
+
+    for entry in entries {
+        writeln!(
+            playlist,
+            "#EXTINF:{},{}\n{}",
+            entry_duration_seconds(entry).map_or(-1, i64::from),
+            entry_display_title(entry),
+            entry.path
+        )
+        .unwrap();
+    }
+
+    playlist
+}
+
+fn format_xspf(title: &str, entries: &[PlaylistEntry]) -> String {
+    let mut playlist = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n"
+    ));
+
+    writeln!(playlist, "  <title>{}</title>", escape_xml(title)).unwrap();
+    playlist.push_str("  <trackList>\n");
+
+    for entry in entries {
+        playlist.push_str("    <track>\n");
+
+        writeln!(
+            playlist,
+            "      <location>{}</location>",
+            escape_xml(&encode_uri_path(&entry.path))
+        )
+        .unwrap();
+
+        writeln!(
+            playlist,
+            "      <title>{}</title>",
+            escape_xml(&entry.track.title)
+        )
+        .unwrap();
+
+        if let Some(artist) = entry.track.artists.first() {
+            writeln!(
+                playlist,
+                "      <creator>{}</creator>",
+                escape_xml(&artist.name)
+            )
+            .unwrap();
+        }
+
+        if let Some(duration_ms) = entry.track.duration_ms {
+            writeln!(playlist, "      <duration>{duration_ms}</duration>").unwrap();
+        }
+
+        playlist.push_str("    </track>\n");
+    }
+
+    playlist.push_str("  </trackList>\n</playlist>\n");
+    playlist
+}
+
+fn format_pls(entries: &[PlaylistEntry]) -> String {
+    let mut playlist = String::from("[playlist]\n");
+
+    for (i, entry) in entries.iter().enumerate() {
+        let i = i + 1;
+
+        writeln!(
+            playlist,
+            "File{i}={}\nTitle{i}={}\nLength{i}={}",
+            entry.path,
+            entry_display_title(entry),
+            entry_duration_seconds(entry).map_or(-1, i64::from)
+        )
+        .unwrap();
+    }
+
+    writeln!(playlist, "NumberOfEntries={}\nVersion=2", entries.len()).unwrap();
+    playlist
+}
+
+fn entry_duration_seconds(entry: &PlaylistEntry) -> Option<u32> {
+    entry
+        .track
+        .duration_ms
+        .map(|duration_ms| u32::try_from(duration_ms.div_ceil(1000)).unwrap_or(u32::MAX))
+}
+
+fn entry_display_title(entry: &PlaylistEntry) -> String {
+    let title = entry.track.title.replace(['\r', '\n'], " ");
+
+    match entry.track.artists.first() {
+        Some(artist) => format!("{} - {title}", artist.name.replace(['\r', '\n'], " ")),
+        None => title,
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(char),
+        }
+    }
+
+    escaped
+}
+
+fn encode_uri_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            write!(encoded, "%{byte:02X}").unwrap();
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,78 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+const QUEUE_FILE_NAME: &str = ".lucida-queue";
+
+/// adds the queue saved in the output directory by a stopped run to the files
+/// to read URLs from when resuming
+pub async fn add_queue_file(
+    mut files: Vec<PathBuf>,
+    output_path: &Path,
+    resume: bool,
+) -> Vec<PathBuf> {
+    if !resume {
+        return files;
+    }
+
+    let queue_path = output_path.join(QUEUE_FILE_NAME);
+
+    if fs::try_exists(&queue_path).await.unwrap() {
+        tracing::info!("resuming the queue saved in {}", queue_path.display());
+        files.push(queue_path);
+    } else {
+        tracing::warn!("no queue to resume in {}", output_path.display());
+    }
+
+    files
+}
+
+/// saves the URLs that weren't finished when the run was stopped, and the
+/// skipped URLs, so they can be downloaded with --resume. the resumed queue is
+/// replaced, a queue that wasn't resumed is added to
+pub async fn update_queue(
+    output_path: &Path,
+    urls: Vec<String>,
+    finished_urls: &HashSet<String>,
+    skipped_urls: &HashSet<String>,
+    is_stopped: bool,
+    is_resumed: bool,
+) {
+    let queue_path = output_path.join(QUEUE_FILE_NAME);
+
+    // the queue is popped from the back
+    let unfinished_urls = urls
+        .into_iter()
+        .rev()
+        .filter(|url| skipped_urls.contains(url) || (is_stopped && !finished_urls.contains(url)))
+        .collect::<Vec<_>>();
+
+    if unfinished_urls.is_empty() {
+        if is_resumed && fs::try_exists(&queue_path).await.unwrap() {
+            fs::remove_file(queue_path).await.unwrap();
+        }
+
+        return;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(!is_resumed)
+        .truncate(is_resumed)
+        .open(&queue_path)
+        .await
+        .unwrap();
+
+    file.write_all((unfinished_urls.join("\n") + "\n").as_bytes())
+        .await
+        .unwrap();
+
+    tracing::warn!(
+        "saved {} unfinished URLs to {}, continue with --resume",
+        unfinished_urls.len(),
+        queue_path.display()
+    );
+}
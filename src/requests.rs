@@ -1,16 +1,43 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
 use std::time::Duration;
 
-use reqwest::header::CONTENT_TYPE;
+use bytes::Bytes;
+use reqwest::header::{CONTENT_TYPE, RANGE};
 use reqwest::{Client, StatusCode, Url};
-use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::mpsc::{self, Receiver};
 use tokio::time;
 
+use tokio_util::sync::CancellationToken;
+
 use crate::models::{
-    Account, Availability, DownloadConfig, Token, Track, TrackDownload, TrackDownloadRequest,
-    TrackDownloadResult, TrackDownloadStatus, Upload,
+    Account, AccountSelection, Availability, DownloadConfig, Quality, RequestTrackError, Service,
+    Token, Track, TrackDownload, TrackDownloadRequest, TrackDownloadResult, TrackDownloadStatus,
+    TrackStream, UnavailableReason, Upload,
 };
+use crate::{controls, limiters};
+
+/// amount of track downloads every account was used for
+static ACCOUNT_DOWNLOADS: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+
+/// amount of received chunks waiting to be written to disk. reading from the
+/// response pauses once the buffer is full, which slows the download down to
+/// the speed of the disk
+const CHUNK_BUFFER_SIZE: usize = 16;
+
+/// errors lucida responds with when the tokens of the page expired
+const TOKEN_ERRORS: [&str; 2] = ["token expired", "expired token"];
+
+/// errors lucida responds with when the track is restricted to other regions
+const REGION_ERRORS: [&str; 3] = [
+    "not available in your region",
+    "not available in your country",
+    "geo-restricted",
+];
+
+/// errors lucida responds with when the track isn't available in the
+/// requested quality
+const QUALITY_ERRORS: [&str; 3] = ["quality", "downscale", "bitrate"];
 
 const IRRECOVERABLE_STATUS_CODES: [StatusCode; 2] =
     [StatusCode::NOT_FOUND, StatusCode::INTERNAL_SERVER_ERROR];
@@ -25,115 +52,230 @@ pub async fn check_availability(client: &Client) -> Availability {
     }
 }
 
+/// requests the page of the URL once, returning its HTML when lucida responds
+/// with 200
 pub async fn resolve_album(
     client: &Client,
     url: &str,
     country: &str,
-    running: &Arc<AtomicBool>,
+    cancel: &CancellationToken,
 ) -> Option<String> {
-    loop {
-        let response = client
-            .get(
-                Url::parse_with_params("https://lucida.to/", &[("url", url), ("country", country)])
-                    .unwrap(),
-            )
-            .send()
-            .await
-            .unwrap();
+    controls::wait_until_resumed(cancel).await;
 
-        let status = response.status();
-
-        if status == StatusCode::OK {
-            break Some(response.text().await.unwrap());
-        }
-
-        tracing::warn!("received code {} when resolving album", status.as_u16());
+    let response = client
+        .get(
+            Url::parse_with_params("https://lucida.to/", &[("url", url), ("country", country)])
+                .unwrap(),
+        )
+        .send()
+        .await
+        .unwrap();
 
-        if !running.load(Ordering::Relaxed) {
-            break None;
-        }
+    let status = response.status();
 
-        time::sleep(Duration::from_secs(5)).await;
+    if status == StatusCode::OK {
+        return Some(response.text().await.unwrap());
     }
+
+    tracing::warn!("received code {} when resolving album", status.as_u16());
+    None
 }
 
 pub async fn request_track_download(
     client: &Client,
+    service: Service,
     track: &Track,
-    token_expiry: u64,
+    token: Token<'_>,
     config: &DownloadConfig,
-    running: Arc<AtomicBool>,
-) -> Option<TrackDownload> {
+    cancel: CancellationToken,
+) -> Result<(TrackDownload, Quality), RequestTrackError> {
+    let accounts = config.accounts(service);
+    let mut quality_index = 0;
+    let mut account_index = 0;
+
     loop {
-        let response = client
-            .post("https://lucida.to/api/load?url=%2Fapi%2Ffetch%2Fstream%2Fv2")
-            .json(&TrackDownloadRequest {
-                account: Account {
-                    id: &config.country,
-                    r#type: "country",
-                },
-                compat: false,
-                downscale: "original",
-                handoff: true,
-                metadata: config.metadata,
-                private: config.private,
-                token: Token {
-                    expiry: token_expiry,
-                    primary: track.csrf.as_deref(),
-                    secondary: track.csrf_fallback.as_deref(),
-                },
-                upload: Upload { enabled: false },
-                url: &track.url,
-            })
-            .send()
-            .await
-            .unwrap();
+        controls::wait_until_resumed(&cancel).await;
 
-        let status = response.status();
+        let quality = config.qualities[quality_index];
+        let account = accounts[account_index];
 
-        if status == StatusCode::OK {
-            if let Ok(track_download) = response.json().await {
-                match track_download {
-                    TrackDownloadResult::Ok(track_download) => break Some(track_download),
-                    TrackDownloadResult::Error { error, .. } => {
-                        tracing::warn!("error when requesting track download: {error}");
-
-                        if !running.load(Ordering::Relaxed) {
-                            break None;
-                        }
+        let result =
+            send_track_download_request(client, account, quality, track, token, config).await;
 
-                        time::sleep(Duration::from_secs(5)).await;
-                    }
+        match result {
+            Ok(TrackDownloadResult::Ok(track_download)) => {
+                if account_index != 0 {
+                    tracing::info!("track {} is available with {account}", track.title);
                 }
-            } else {
-                tracing::warn!("invalid JSON when requesting track download");
 
-                if !running.load(Ordering::Relaxed) {
-                    break None;
-                }
+                *ACCOUNT_DOWNLOADS
+                    .lock()
+                    .unwrap()
+                    .entry(account.to_string())
+                    .or_default() += 1;
 
-                time::sleep(Duration::from_secs(5)).await;
+                break Ok((track_download, quality));
             }
-        } else {
-            tracing::warn!(
-                "received code {} when requesting track download",
-                status.as_u16()
-            );
-
-            if !running.load(Ordering::Relaxed) {
-                break None;
+            Ok(TrackDownloadResult::Error { error, .. }) => {
+                if is_token_error(&error) {
+                    break Err(RequestTrackError::TokenExpired);
+                }
+
+                if is_region_error(&error) {
+                    let Some(next_account) = accounts.get(account_index + 1) else {
+                        tracing::warn!(
+                            "error when requesting track download with {account}: {error}"
+                        );
+
+                        break Err(RequestTrackError::Unavailable(
+                            UnavailableReason::RegionRestricted,
+                        ));
+                    };
+
+                    tracing::warn!(
+                        "error when requesting track download with {account}, falling back to {next_account}: {error}"
+                    );
+
+                    account_index += 1;
+                    continue;
+                }
+
+                if is_quality_error(&error) {
+                    let Some(&next_quality) = config.qualities.get(quality_index + 1) else {
+                        tracing::warn!(
+                            "error when requesting track download in {} quality: {error}",
+                            quality.downscale()
+                        );
+
+                        break Err(RequestTrackError::Unavailable(
+                            UnavailableReason::QualityRefused,
+                        ));
+                    };
+
+                    tracing::warn!(
+                        "error when requesting track download in {} quality, falling back to {}: {error}",
+                        quality.downscale(),
+                        next_quality.downscale()
+                    );
+
+                    // the next quality may be available with the preferred
+                    // accounts
+                    quality_index += 1;
+                    account_index = 0;
+                    continue;
+                }
+
+                tracing::warn!("error when requesting track download: {error}");
             }
+            Err(message) => tracing::warn!("{message}"),
+        }
 
-            time::sleep(Duration::from_secs(5)).await;
+        // other errors are retried with the same quality
+        if cancel
+            .run_until_cancelled(time::sleep(Duration::from_secs(5)))
+            .await
+            .is_none()
+        {
+            break Err(RequestTrackError::Stopped);
         }
     }
 }
 
+async fn send_track_download_request(
+    client: &Client,
+    account: &AccountSelection,
+    quality: Quality,
+    track: &Track,
+    token: Token<'_>,
+    config: &DownloadConfig,
+) -> Result<TrackDownloadResult, String> {
+    let response = client
+        .post("https://lucida.to/api/load?url=%2Fapi%2Ffetch%2Fstream%2Fv2")
+        .json(&TrackDownloadRequest {
+            account: Account {
+                id: &account.id,
+                r#type: account.r#type,
+            },
+            compat: config.compat,
+            downscale: quality.downscale(),
+            handoff: true,
+            metadata: config.metadata,
+            private: config.private,
+            token,
+            upload: Upload { enabled: false },
+            url: &track.url,
+        })
+        .send()
+        .await
+        .unwrap();
+
+    let status = response.status();
+
+    if status != StatusCode::OK {
+        return Err(format!(
+            "received code {} when requesting track download",
+            status.as_u16()
+        ));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|_| "invalid JSON when requesting track download".to_owned())
+}
+
+/// logs how many track downloads every account was used for, to help with
+/// ordering the accounts
+pub fn report_accounts() {
+    let report = ACCOUNT_DOWNLOADS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(country, count)| format!("{country}: {count}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if !report.is_empty() {
+        tracing::info!("track downloads by account: {report}");
+    }
+}
+
+/// whether the error means that the tokens of the page expired
+fn is_token_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+
+    TOKEN_ERRORS
+        .into_iter()
+        .any(|message| error.contains(message))
+}
+
+/// whether the error means that lucida refused the requested quality
+fn is_quality_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+
+    QUALITY_ERRORS
+        .into_iter()
+        .any(|message| error.contains(message))
+}
+
+/// whether the error means that the track isn't available with the account
+/// because of the region of the account
+fn is_region_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+
+    REGION_ERRORS
+        .into_iter()
+        .any(|message| error.contains(message))
+}
+
 pub async fn track_download_status(
     client: &Client,
     stream: &TrackDownload,
+    cancel: &CancellationToken,
 ) -> Option<TrackDownloadStatus> {
     loop {
+        controls::wait_until_resumed(cancel).await;
+
         let response = client
             .get(format!(
                 "https://{}.lucida.to/api/fetch/request/{}",
@@ -162,49 +304,76 @@ pub async fn track_download_status(
     }
 }
 
+/// downloads the track audio, starting at `offset` to resume an interrupted
+/// download if lucida supports it
 pub async fn download_track(
     client: &Client,
     stream: &TrackDownload,
-) -> Option<(UnboundedReceiver<Result<Vec<u8>, ()>>, String)> {
+    offset: u64,
+    cancel: &CancellationToken,
+) -> Option<TrackStream> {
     loop {
-        let mut response = client
-            .get(format!(
-                "https://{}.lucida.to/api/fetch/request/{}/download",
-                stream.server, stream.handoff
-            ))
-            .send()
-            .await
-            .unwrap();
+        controls::wait_until_resumed(cancel).await;
+
+        let mut request = client.get(format!(
+            "https://{}.lucida.to/api/fetch/request/{}/download",
+            stream.server, stream.handoff
+        ));
 
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+
+        let mut response = request.send().await.unwrap();
         let status = response.status();
 
-        if status == StatusCode::OK {
+        if status == StatusCode::OK || (offset > 0 && status == StatusCode::PARTIAL_CONTENT) {
             let mime_type = response.headers()[CONTENT_TYPE]
                 .to_str()
                 .unwrap()
                 .to_owned();
 
-            let (tx, rx) = mpsc::unbounded_channel();
+            let is_resumed = status == StatusCode::PARTIAL_CONTENT;
+
+            let content_length = response
+                .content_length()
+                .map(|length| if is_resumed { offset + length } else { length });
+            let (tx, rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+            let cancel = cancel.clone();
 
             tokio::spawn(async move {
                 loop {
-                    let result = response.chunk().await;
+                    controls::wait_until_resumed(&cancel).await;
+
+                    let Some(result) = cancel.run_until_cancelled(response.chunk()).await else {
+                        break;
+                    };
 
                     match result {
-                        Ok(chunk) => match chunk {
-                            Some(chunk) => tx.send(Ok(chunk.to_vec())).unwrap(),
-                            None => break,
-                        },
+                        Ok(Some(chunk)) => {
+                            limiters::throttle(chunk.len()).await;
+
+                            // the receiver is gone when the download was abandoned
+                            if tx.send(Ok(chunk)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
                         Err(err) => {
                             tracing::warn!("error when downloading track audio: {err}");
-                            tx.send(Err(())).unwrap();
+                            tx.send(Err(())).await.ok();
                             break;
                         }
                     }
                 }
             });
 
-            break Some((rx, mime_type));
+            break Some(TrackStream {
+                chunks: rx,
+                mime_type,
+                content_length,
+                is_resumed,
+            });
         }
 
         tracing::warn!(
@@ -212,7 +381,7 @@ pub async fn download_track(
             status.as_u16()
         );
 
-        if IRRECOVERABLE_STATUS_CODES.contains(&status) {
+        if IRRECOVERABLE_STATUS_CODES.contains(&status) || cancel.is_cancelled() {
             break None;
         }
 
@@ -223,28 +392,40 @@ pub async fn download_track(
 pub async fn download_album_cover(
     client: &Client,
     url: &str,
-    running: Arc<AtomicBool>,
-) -> Option<UnboundedReceiver<Result<Vec<u8>, ()>>> {
+    cancel: CancellationToken,
+) -> Option<Receiver<Result<Bytes, ()>>> {
     loop {
+        controls::wait_until_resumed(&cancel).await;
+
         let mut response = client.get(url).send().await.unwrap();
         let status = response.status();
 
         if status == StatusCode::OK {
-            let (tx, rx) = mpsc::unbounded_channel();
+            let (tx, rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+            let cancel = cancel.clone();
 
             tokio::spawn(async move {
                 loop {
-                    let result = response.chunk().await;
+                    controls::wait_until_resumed(&cancel).await;
+
+                    let Some(result) = cancel.run_until_cancelled(response.chunk()).await else {
+                        break;
+                    };
 
                     match result {
-                        Ok(chunk) => match chunk {
-                            Some(chunk) => tx.send(Ok(chunk.to_vec())).unwrap(),
-                            None => break,
-                        },
+                        Ok(Some(chunk)) => {
+                            limiters::throttle(chunk.len()).await;
+
+                            // the receiver is gone when the download was abandoned
+                            if tx.send(Ok(chunk)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
                         Err(err) => {
                             tracing::warn!("error when downloading album cover: {err}");
 
-                            tx.send(Err(())).unwrap();
+                            tx.send(Err(())).await.ok();
                             break;
                         }
                     }
@@ -262,7 +443,7 @@ pub async fn download_album_cover(
             status.as_u16()
         );
 
-        if !running.load(Ordering::Relaxed) {
+        if cancel.is_cancelled() {
             return None;
         }
     }
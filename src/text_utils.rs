@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::path::{Component, Path};
 
 use crate::models::Track;
 
@@ -7,6 +8,29 @@ pub fn sanitize_file_name(name: &str) -> String {
         .replace(['\\', '/', ':', '*', '?', '"', '<', '>', '|'], "_")
 }
 
+/// formats `path` relative to `base`, always using `/` as the separator
+pub fn relative_path(base: &Path, path: &Path) -> String {
+    let mut base_components = base.components().peekable();
+    let mut path_components = path.components().peekable();
+
+    while let (Some(base_component), Some(path_component)) =
+        (base_components.peek(), path_components.peek())
+        && base_component == path_component
+    {
+        base_components.next();
+        path_components.next();
+    }
+
+    base_components
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .map(|_| "..".into())
+        .chain(
+            path_components.map(|component| component.as_os_str().to_string_lossy().into_owned()),
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 pub fn parse_enclosed_value<'a>(start_marker: &str, end_marker: &str, text: &'a str) -> &'a str {
     let start_index = text
         .find(start_marker)
@@ -56,3 +80,38 @@ pub fn format_track_stem(
         sanitize_file_name(&track.title)
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        let paths = [
+            ("out/playlists", "out/playlists/a.flac", "a.flac"),
+            (
+                "out/playlists",
+                "out/Artist/Album/01.flac",
+                "../Artist/Album/01.flac",
+            ),
+            ("out/a/b", "out/c.flac", "../../c.flac"),
+            (
+                "/music/playlists/",
+                "/music//Album/./01.flac",
+                "../Album/01.flac",
+            ),
+            ("out", "out", ""),
+            ("out/playlists", "out", ".."),
+            ("", "Album/01.flac", "Album/01.flac"),
+            ("a", "b", "../b"),
+        ];
+
+        for (base, path, relative_path_text) in paths {
+            assert_eq!(
+                relative_path(Path::new(base), Path::new(path)),
+                relative_path_text,
+                "{base} {path}"
+            );
+        }
+    }
+}
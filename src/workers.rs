@@ -1,54 +1,204 @@
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use reqwest::Client;
+use tokio::sync::{self, mpsc};
+use tokio::task::JoinHandle;
 
-use crate::downloaders;
-use crate::models::{AlbumYear, DownloadConfig, Service, SkipConfig, Track};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
+use crate::filters::TrackFilter;
+use crate::models::{
+    DirectoryConfig, DownloadConfig, DownloadedTrack, ExistingFiles, PageTokens, PlaylistFormat,
+    ProcessedTrack, RemovedTracks, ResolveAlbumError, ResolvedAlbum, Service, SkipConfig, Track,
+    TrackHandoff, TrackLocation, WorkerConfig,
+};
+use crate::{controls, downloaders};
+
+/// downloads the resolved albums. returns the URLs of the finished albums and
+/// of the albums that timed out
 #[expect(
     clippy::too_many_arguments,
     reason = "this function is called from a single place"
 )]
 pub async fn run_album_worker(
     client: Client,
-    urls: Arc<Mutex<Vec<String>>>,
+    albums: Arc<sync::Mutex<mpsc::Receiver<ResolvedAlbum>>>,
     output_path: PathBuf,
-    force_download: bool,
-    group_singles: bool,
-    album_year: Option<AlbumYear>,
-    flatten_directories: bool,
+    existing_files: ExistingFiles,
+    directories: DirectoryConfig,
     config: DownloadConfig,
-    track_workers: usize,
+    worker_config: WorkerConfig,
+    filter: Arc<TrackFilter>,
     skip: SkipConfig,
-    running: Arc<AtomicBool>,
-) {
-    while running.load(Ordering::Relaxed) {
-        let Some(url) = urls.lock().unwrap().pop() else {
+    playlist_formats: Arc<[PlaylistFormat]>,
+    sync: Option<RemovedTracks>,
+    album_timeout: Option<Duration>,
+    cancel: CancellationToken,
+) -> (Vec<String>, Vec<String>) {
+    let mut finished_urls = Vec::new();
+    let mut timed_out_urls = Vec::new();
+
+    while !cancel.is_cancelled() {
+        let Some(album) = albums.lock().await.recv().await else {
             tracing::info!("stopped: no queued albums");
-            return;
+            return (finished_urls, timed_out_urls);
         };
 
+        let url = album.tokens.url.clone();
+        let album_cancel = cancel.child_token();
+
+        if let Some(album_timeout) = album_timeout {
+            tokio::spawn(time_out_album(
+                album_cancel.clone(),
+                album_timeout,
+                url.clone(),
+            ));
+        }
+
         downloaders::download_album(
             client.clone(),
-            &url,
+            album,
             &output_path,
-            force_download,
-            group_singles,
-            album_year,
-            flatten_directories,
+            existing_files,
+            directories,
             config.clone(),
-            track_workers,
+            worker_config,
+            &filter,
             skip,
-            running.clone(),
+            playlist_formats.clone(),
+            sync,
+            album_cancel.clone(),
         )
         .await;
+
+        // albums stopped in the middle are saved to be resumed when the run was
+        // stopped, albums that timed out are saved either way
+        if !album_cancel.is_cancelled() {
+            finished_urls.push(url);
+        } else if !cancel.is_cancelled() {
+            timed_out_urls.push(url);
+        }
+
+        // stops the timeout
+        album_cancel.cancel();
     }
 
     tracing::info!("stopped");
+    (finished_urls, timed_out_urls)
+}
+
+/// skips the album once downloading it takes longer than the timeout. the time
+/// the downloads are paused for isn't counted
+async fn time_out_album(cancel: CancellationToken, timeout: Duration, url: String) {
+    if cancel
+        .run_until_cancelled(controls::sleep_running(timeout))
+        .await
+        .is_some()
+    {
+        tracing::warn!(
+            "skipping album {url}, it took longer than {} seconds. it will be saved to continue with --resume",
+            timeout.as_secs()
+        );
+
+        cancel.cancel();
+    }
+}
+
+/// spawns the album resolvers and returns the channel they send the resolved
+/// albums to. the albums are resolved simultaneously, so a URL lucida keeps
+/// failing to resolve doesn't hold up the others
+#[expect(clippy::type_complexity)]
+pub fn spawn_album_resolvers(
+    client: &Client,
+    prefetch_albums: usize,
+    urls: &Arc<Mutex<Vec<String>>>,
+    config: &DownloadConfig,
+    expand_tracks: bool,
+    cancel: &CancellationToken,
+) -> (
+    Vec<JoinHandle<(Vec<String>, Vec<String>)>>,
+    mpsc::Receiver<ResolvedAlbum>,
+) {
+    let (albums_tx, albums_rx) = mpsc::channel(prefetch_albums);
+    let resolver_count = prefetch_albums.min(urls.lock().unwrap().len());
+
+    // the album workers stop once all resolvers dropped their senders
+    let resolvers = (1..=resolver_count)
+        .map(|resolver| {
+            tokio::spawn(
+                run_album_resolver(
+                    client.clone(),
+                    urls.clone(),
+                    config.clone(),
+                    expand_tracks,
+                    albums_tx.clone(),
+                    cancel.clone(),
+                )
+                .instrument(tracing::info_span!("resolver", resolver)),
+            )
+        })
+        .collect();
+
+    (resolvers, albums_rx)
+}
+
+/// resolves the queued albums ahead of the album workers, which wait for them
+/// once the channel is full. returns the URLs that couldn't be resolved and
+/// the URLs that can never be downloaded
+async fn run_album_resolver(
+    client: Client,
+    urls: Arc<Mutex<Vec<String>>>,
+    config: DownloadConfig,
+    expand_tracks: bool,
+    albums: mpsc::Sender<ResolvedAlbum>,
+    cancel: CancellationToken,
+) -> (Vec<String>, Vec<String>) {
+    let mut unresolved_urls = Vec::new();
+    let mut invalid_urls = Vec::new();
+
+    while !cancel.is_cancelled() {
+        let Some(url) = urls.lock().unwrap().pop() else {
+            break;
+        };
+
+        let album =
+            match downloaders::resolve_album_info(&client, &url, &config, expand_tracks, &cancel)
+                .await
+            {
+                Ok(album) => album,
+                Err(ResolveAlbumError::Unresolved) => {
+                    if !cancel.is_cancelled() {
+                        tracing::error!("could not resolve {url}");
+                        unresolved_urls.push(url);
+                    }
+
+                    continue;
+                }
+                Err(ResolveAlbumError::ArtistUrl { .. }) => {
+                    invalid_urls.push(url);
+                    continue;
+                }
+            };
+
+        tracing::info!(
+            "resolved album {} - {}, {} albums queued",
+            album.info.artist_name,
+            album.info.title,
+            urls.lock().unwrap().len()
+        );
+
+        if albums.send(album).await.is_err() {
+            break;
+        }
+    }
+
+    (unresolved_urls, invalid_urls)
 }
 
+/// has lucida process tracks and passes them on to the download workers
 #[expect(clippy::type_complexity)]
 #[expect(
     clippy::too_many_arguments,
@@ -57,33 +207,77 @@ pub async fn run_album_worker(
 pub async fn run_track_worker(
     client: Client,
     service: Service,
-    tracks: Arc<Mutex<Vec<(Option<u32>, Track)>>>,
-    track_count: u32,
-    is_grouped_single: bool,
-    token_expiry: u64,
-    force_download: bool,
+    tracks: Arc<Mutex<Vec<(Option<u32>, Track, TrackLocation)>>>,
+    tokens: Arc<sync::Mutex<PageTokens>>,
+    existing_files: ExistingFiles,
     config: DownloadConfig,
-    album_path: Arc<PathBuf>,
-    running: Arc<AtomicBool>,
-) {
-    while running.load(Ordering::Relaxed) {
-        let Some((track_number, track)) = tracks.lock().unwrap().pop() else {
-            return;
+    handoffs: mpsc::Sender<(Option<u32>, Track, TrackHandoff)>,
+    cancel: CancellationToken,
+) -> Vec<DownloadedTrack> {
+    let mut downloaded_tracks = Vec::new();
+
+    while !cancel.is_cancelled() {
+        let Some((track_number, track, location)) = tracks.lock().unwrap().pop() else {
+            break;
         };
 
-        downloaders::request_and_download_track(
+        match downloaders::process_track(
             client.clone(),
             service,
             &track,
-            track_number,
-            track_count,
-            is_grouped_single,
-            token_expiry,
-            force_download,
+            &location,
+            &tokens,
+            existing_files,
             &config,
-            album_path.clone(),
-            running.clone(),
+            cancel.child_token(),
         )
-        .await;
+        .await
+        {
+            Some(ProcessedTrack::Existing(path)) => downloaded_tracks.push(DownloadedTrack {
+                track_number,
+                track,
+                path,
+                quality: None,
+            }),
+            Some(ProcessedTrack::Ready(handoff)) => {
+                handoffs.send((track_number, track, handoff)).await.unwrap();
+            }
+            None => {}
+        }
     }
+
+    downloaded_tracks
+}
+
+/// downloads the tracks processed by the track workers until all of them
+/// stopped
+#[expect(clippy::type_complexity)]
+pub async fn run_download_worker(
+    client: Client,
+    handoffs: Arc<sync::Mutex<mpsc::Receiver<(Option<u32>, Track, TrackHandoff)>>>,
+) -> Vec<DownloadedTrack> {
+    let mut downloaded_tracks = Vec::new();
+
+    loop {
+        // the lock is released before downloading, so the workers download in
+        // parallel
+        let handoff = handoffs.lock().await.recv().await;
+
+        let Some((track_number, track, handoff)) = handoff else {
+            break;
+        };
+
+        tracing::info!("downloading track {}", track.title);
+
+        if let Some((path, quality)) = downloaders::download_track(client.clone(), handoff).await {
+            downloaded_tracks.push(DownloadedTrack {
+                track_number,
+                track,
+                path,
+                quality,
+            });
+        }
+    }
+
+    downloaded_tracks
 }